@@ -32,15 +32,69 @@ typedef struct CCSParameters {
   bool jpeg_progressive = true;
   bool jpeg_optimize = false;
   bool jpeg_preserve_icc = true;
+  bool jpeg_arithmetic_coding = false;
+  uint32_t jpeg_restart_interval = 0; // MCUs between restart markers, 0 to disable
+  uint32_t jpeg_restart_in_rows = 0; // MCU rows between restart markers, overrides jpeg_restart_interval
+  bool jpeg_optimize_scans = true;
   uint32_t  png_quality = 80;
   uint32_t  png_optimization_level = 3;
   bool  png_force_zopfli = false;
   bool  png_optimize = false;
+  uint32_t  png_min_quality = 0;
+  uint32_t  png_quality_fallback = 1; // support 0:Error Other Int:Lossless
+  float  png_dithering_level = 1.0f;
+  uint32_t  png_speed = 4; // support 1-10
+  uint32_t  png_max_colors = 256; // support 2-256
+  uint32_t  png_posterization = 0; // support 0-4
+  bool  png_last_index_transparent = false;
+  bool  png_optimize_lossy = true;
+  uint32_t  png_high_bit_depth = 1; // support 0:Error 2:Quantize Other Int:Lossless
+  bool  png_reduce_bit_depth = true;
+  bool  png_reduce_color_type = true;
+  bool  png_reduce_palette = true;
+  bool  png_reduce_grayscale = true;
+  bool  png_reduce_alpha = false;
+  uint32_t  png_interlace = 2; // support 0:Keep 1:Force Other Int:Remove
+  uint32_t  png_timeout = 0; // milliseconds, 0:No timeout
+  uint32_t  png_zopfli_iterations = 0; // support 0:Auto 1-255
+  uint32_t  png_deflate_level = 0; // support 0:Auto 1-12
   uint32_t  gif_quality = 80;
+  bool  gif_optimize = false;
+  uint32_t  gif_lossy_quality = 0; // support 0:Same as gif_quality 1-100
+  uint32_t  gif_motion_quality = 0; // support 0:Same as gif_quality 1-100
+  bool  gif_fast = false;
+  bool  gif_extra_effort = false;
+  bool  gif_preserve_delays = false;
+  uint32_t  gif_max_frame_rate = 0; // frames per second, 0:No limit
+  uint32_t  gif_max_frames = 0; // 0:No limit
+  uint32_t  gif_trim_start = 0; // milliseconds
+  uint32_t  gif_trim_end = 0; // milliseconds, 0:Until the end
+  bool  gif_keep_extensions = false;
   uint32_t  webp_quality = 80;
   bool webp_lossless = false;
-  uint32_t  tiff_compression = 2; // support 1:Lzw 2:Deflate 3:Packbits Other Int:Uncompressed
+  uint32_t  webp_method = 4; // support 0-6
+  uint32_t  webp_sns_strength = 50; // support 0-100
+  uint32_t  webp_filter_strength = 60; // support 0-100
+  uint32_t  webp_filter_sharpness = 0; // support 0-7
+  uint32_t  webp_segments = 4; // support 1-4
+  uint32_t  webp_preprocessing = 0; // support 0:None 1:Segment smoothing 2:Pseudo-random dithering
+  uint32_t  webp_alpha_quality = 100; // support 0-100
+  uint32_t  webp_near_lossless = 100; // support 0-100, 100:Off
+  bool  webp_exact = false;
+  uint32_t  webp_pass = 1; // support 1-10
+  uint32_t  webp_image_hint = 0; // support 0:Default 1:Picture 2:Photo 3:Graph
+  uint32_t  webp_target_size = 0; // bytes, 0:Off
+  float  webp_target_psnr = 0.0f; // dB, 0:Off
+  uint32_t  webp_kmin = 0;
+  uint32_t  webp_kmax = 0; // support 0:No key frames 1:All key frames
+  bool  webp_allow_mixed = false;
+  bool  webp_minimize_size = false;
+  uint32_t  tiff_compression = 2; // support 1:Lzw 2:Deflate 3:Packbits 4:Jpeg 5:CcittGroup3 6:CcittGroup4 Other Int:Uncompressed
   uint32_t  tiff_deflate_level = 6; // support 1:Fast 6:Balanced Other Int:Best
+  uint32_t  tiff_color_type = 0; // support 1:Rgb8 2:Rgba8 3:Gray8 Other Int:Original
+  uint32_t  tiff_predictor = 1; // support 2:Horizontal Other Int:None
+  uint32_t  tiff_jpeg_quality = 80; // support 0-100
+  uint32_t  tiff_threshold = 0; // support 1:Adaptive Other Int:Global
   uint32_t  width = 0;
   uint32_t  height = 0;
 } CCSParameters;
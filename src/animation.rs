@@ -0,0 +1,45 @@
+use image::imageops::FilterType;
+use image::RgbaImage;
+
+use crate::resize::compute_dimensions;
+
+/// A fully composited frame of an animation and how long it is displayed.
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub delay_ms: u32,
+}
+
+/// An animation decoded to full canvas RGBA frames, used to move animations between formats.
+///
+/// `loop_count` is the number of times the animation is played, 0 meaning forever. `background` is the RGBA
+/// canvas color suggested by the source, if any.
+pub struct Animation {
+    pub width: u32,
+    pub height: u32,
+    pub loop_count: u32,
+    pub background: Option<[u8; 4]>,
+    pub frames: Vec<AnimationFrame>,
+}
+
+impl Animation {
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width == 0 && height == 0 {
+            return;
+        }
+
+        let (new_width, new_height) = compute_dimensions(self.width, self.height, width, height);
+        if new_width == self.width && new_height == self.height {
+            return;
+        }
+
+        for frame in self.frames.iter_mut() {
+            frame.image = image::imageops::resize(&frame.image, new_width, new_height, FilterType::Lanczos3);
+        }
+        self.width = new_width;
+        self.height = new_height;
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+}
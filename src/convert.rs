@@ -1,10 +1,12 @@
 use std::io::Cursor;
 
 use bytes::Bytes;
-use image::{ColorType, DynamicImage, ImageFormat, ImageReader};
+use image::{ColorType, DynamicImage, ImageFormat, ImageReader, RgbaImage};
 use img_parts::{DynImage, ImageEXIF, ImageICC};
 
+use crate::animation::Animation;
 use crate::error::CaesiumError;
+use crate::parameters::FrameSelection;
 use crate::utils::{get_filetype_from_memory, get_jpeg_orientation};
 use crate::{compress_in_memory, CSParameters, SupportedFileTypes};
 
@@ -31,6 +33,10 @@ pub fn convert_in_memory(
         }
     }
 
+    if let Some(animation) = decode_animation(&in_file, original_file_type, format)? {
+        return convert_animation(animation, format, parameters);
+    }
+
     let i = in_file.as_slice();
     let mut original_image = ImageReader::new(Cursor::new(i))
         .with_guessed_format()
@@ -121,6 +127,176 @@ pub fn convert_in_memory(
     }
 }
 
+/// Converts one frame of an animated GIF, WebP or PNG to a still image, compositing the frames before it.
+///
+/// Still images only have a first frame, which is converted as is.
+pub fn extract_frame(
+    in_file: Vec<u8>,
+    frame: FrameSelection,
+    format: SupportedFileTypes,
+    parameters: &CSParameters,
+) -> Result<Vec<u8>, CaesiumError> {
+    let animation = match get_filetype_from_memory(&in_file) {
+        #[cfg(feature = "gif")]
+        SupportedFileTypes::Gif => Some(crate::gif::decode_animation(&in_file)?),
+        #[cfg(feature = "webp")]
+        SupportedFileTypes::WebP => Some(crate::webp::decode_animation(&in_file)?),
+        #[cfg(feature = "png")]
+        SupportedFileTypes::Png if crate::utils::is_apng(&in_file) => Some(crate::png::decode_apng(&in_file)?),
+        _ => None,
+    };
+
+    let Some(animation) = animation else {
+        if let FrameSelection::Index(index) = frame {
+            if index > 0 {
+                return Err(CaesiumError {
+                    message: "Frame index out of range".into(),
+                    code: 10415,
+                });
+            }
+        }
+        return convert_in_memory(in_file, format, parameters, true);
+    };
+
+    let image = select_frame(animation, frame)?;
+    let mut still_image = Vec::new();
+    DynamicImage::ImageRgba8(image)
+        .write_to(&mut Cursor::new(&mut still_image), ImageFormat::Png)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 10417,
+        })?;
+
+    convert_in_memory(still_image, format, parameters, true)
+}
+
+fn select_frame(animation: Animation, frame: FrameSelection) -> Result<RgbaImage, CaesiumError> {
+    if animation.frames.is_empty() {
+        return Err(CaesiumError {
+            message: "Animation has no frames".into(),
+            code: 10415,
+        });
+    }
+
+    let index = match frame {
+        FrameSelection::Index(index) if index < animation.frames.len() => index,
+        FrameSelection::Index(_) => {
+            return Err(CaesiumError {
+                message: "Frame index out of range".into(),
+                code: 10415,
+            })
+        }
+        FrameSelection::Time(time) => {
+            let mut end = 0;
+            animation
+                .frames
+                .iter()
+                .position(|f| {
+                    end += f.delay_ms as u128;
+                    time.as_millis() < end
+                })
+                .ok_or(CaesiumError {
+                    message: "Time is past the end of the animation".into(),
+                    code: 10416,
+                })?
+        }
+        FrameSelection::Representative => representative_frame(&animation),
+    };
+
+    Ok(animation.frames.into_iter().nth(index).unwrap().image)
+}
+
+/// Finds the frame closest to the average of all frames, compared on small thumbnails.
+fn representative_frame(animation: &Animation) -> usize {
+    let thumbnails: Vec<RgbaImage> = animation
+        .frames
+        .iter()
+        .map(|f| image::imageops::thumbnail(&f.image, 32, 32))
+        .collect();
+
+    let mut average = vec![0.0; thumbnails[0].len()];
+    for thumbnail in &thumbnails {
+        for (sum, value) in average.iter_mut().zip(thumbnail.iter()) {
+            *sum += *value as f64 / thumbnails.len() as f64;
+        }
+    }
+
+    let distance = |thumbnail: &RgbaImage| -> f64 {
+        average
+            .iter()
+            .zip(thumbnail.iter())
+            .map(|(a, v)| (a - *v as f64).powi(2))
+            .sum()
+    };
+    let mut best = 0;
+    let mut best_distance = f64::MAX;
+    for (i, thumbnail) in thumbnails.iter().enumerate() {
+        let d = distance(thumbnail);
+        if d < best_distance {
+            best = i;
+            best_distance = d;
+        }
+    }
+
+    best
+}
+
+/// Decodes the input as an animation when it has more than one frame and the conversion can keep it.
+#[allow(unused_variables)]
+fn decode_animation(
+    in_file: &[u8],
+    from: SupportedFileTypes,
+    to: SupportedFileTypes,
+) -> Result<Option<Animation>, CaesiumError> {
+    let animation = match (from, to) {
+        #[cfg(all(feature = "png", feature = "gif"))]
+        (SupportedFileTypes::Gif, SupportedFileTypes::Png) => Some(crate::gif::decode_animation(in_file)?),
+        #[cfg(all(feature = "png", feature = "webp"))]
+        (SupportedFileTypes::WebP, SupportedFileTypes::Png) => Some(crate::webp::decode_animation(in_file)?),
+        #[cfg(all(feature = "gif", feature = "webp"))]
+        (SupportedFileTypes::Gif, SupportedFileTypes::WebP) => Some(crate::gif::decode_animation(in_file)?),
+        #[cfg(all(feature = "gif", feature = "webp"))]
+        (SupportedFileTypes::WebP, SupportedFileTypes::Gif) => Some(crate::webp::decode_animation(in_file)?),
+        #[cfg(feature = "png")]
+        (SupportedFileTypes::Png, SupportedFileTypes::Gif | SupportedFileTypes::WebP)
+            if crate::utils::is_apng(in_file) =>
+        {
+            Some(crate::png::decode_apng(in_file)?)
+        }
+        _ => None,
+    };
+
+    Ok(animation.filter(Animation::is_animated))
+}
+
+fn convert_animation(
+    mut animation: Animation,
+    format: SupportedFileTypes,
+    parameters: &CSParameters,
+) -> Result<Vec<u8>, CaesiumError> {
+    animation.resize(parameters.width, parameters.height);
+
+    match format {
+        #[cfg(feature = "png")]
+        SupportedFileTypes::Png => {
+            let parameters = CSParameters {
+                width: 0,
+                height: 0,
+                ..parameters.clone()
+            };
+            crate::png::compress_in_memory(&crate::png::encode_apng(&animation)?, &parameters)
+        }
+        #[cfg(feature = "gif")]
+        SupportedFileTypes::Gif => crate::gif::encode_animation(&animation, parameters),
+        #[cfg(feature = "webp")]
+        SupportedFileTypes::WebP => crate::webp::encode_animation(&animation, parameters),
+        _ => Err(CaesiumError {
+            message: "Output format does not support animation".into(),
+            code: 10414,
+        }),
+    }
+}
+
 fn map_image_format(format: SupportedFileTypes) -> Result<ImageFormat, CaesiumError> {
     let image_format = match format {
         SupportedFileTypes::Jpeg => ImageFormat::Jpeg,
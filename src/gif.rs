@@ -1,10 +1,18 @@
+use crate::animation::{Animation, AnimationFrame};
 use crate::error::CaesiumError;
+use crate::probe::GifApplicationExtension;
 use crate::resize::compute_dimensions;
 use crate::CSParameters;
-use gifski::{progress, Settings};
+use gif::Repeat;
+use gif_dispose::RGBA8;
+use gifski::{progress, Collector, Settings};
+use imgref::ImgVec;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::io::Write;
+use std::ops::Range;
 
 pub fn compress(input_path: String, output_path: String, parameters: &CSParameters) -> Result<(), CaesiumError> {
     let in_file = fs::read(input_path).map_err(|e| CaesiumError {
@@ -28,11 +36,199 @@ pub fn compress(input_path: String, output_path: String, parameters: &CSParamete
 }
 
 pub fn compress_in_memory(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
-    let compressed = lossy(in_file, parameters)?;
+    // Resizing has to go through gifski, the original palettes cannot hold the resampled colors
+    let compressed = if parameters.gif.optimize && parameters.width == 0 && parameters.height == 0 {
+        lossless(in_file)?
+    } else {
+        lossy(in_file, parameters)?
+    };
+
+    if parameters.gif.keep_extensions && compressed != *in_file {
+        return copy_extensions(in_file, &compressed);
+    }
 
     Ok(compressed)
 }
 
+/// Applies a level of the size search, from 1 to `quality + 100`.
+///
+/// Levels above `quality` lower the lossy and motion quality while keeping `quality`; the levels below lower
+/// `quality` itself, with both at their lowest.
+pub fn set_size_level(parameters: &mut CSParameters, quality: u32, level: u32) {
+    let lossy_quality = level.saturating_sub(quality).max(1);
+    parameters.gif.lossy_quality = lossy_quality;
+    parameters.gif.motion_quality = lossy_quality;
+    parameters.gif.quality = level.min(quality);
+}
+
+/// Comment and application extensions of a GIF, see [`read_extensions`].
+pub struct GifExtensions {
+    pub comments: Vec<String>,
+    pub application_extensions: Vec<GifApplicationExtension>,
+    pub loop_count: Option<u16>,
+}
+
+/// Reads the comment and application extensions of a GIF, including the loop count when there is one.
+pub fn read_extensions(in_file: &[u8]) -> Result<GifExtensions, CaesiumError> {
+    let (_, blocks) = gif_blocks(in_file)?;
+    let mut extensions = GifExtensions {
+        comments: vec![],
+        application_extensions: vec![],
+        loop_count: None,
+    };
+
+    for (label, block) in blocks {
+        let block = &in_file[block];
+        match label {
+            COMMENT_LABEL => {
+                let comment: Vec<u8> = sub_blocks(&block[2..]).flatten().copied().collect();
+                extensions.comments.push(String::from_utf8_lossy(&comment).into_owned());
+            }
+            APPLICATION_LABEL if block.len() >= 14 && block[2] == 11 => {
+                let identifier = String::from_utf8_lossy(&block[3..11]).into_owned();
+                let data = block[14..block.len() - 1].to_vec();
+                if is_loop_extension(&block[3..14]) {
+                    if let Some([1, low, high]) = sub_blocks(&block[14..]).next() {
+                        extensions.loop_count = Some(u16::from_le_bytes([*low, *high]));
+                    }
+                }
+                extensions.application_extensions.push(GifApplicationExtension {
+                    identifier,
+                    authentication_code: [block[11], block[12], block[13]],
+                    data,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(extensions)
+}
+
+/// Replaces the comment and application extensions of `out_file` with the ones of `in_file`.
+///
+/// Each extension goes before the same frame index as in the input, or before the trailer when the output has
+/// fewer frames. The original loop extension is carried over as is, so the number of times the animation plays
+/// does not depend on how it was decoded.
+fn copy_extensions(in_file: &[u8], out_file: &[u8]) -> Result<Vec<u8>, CaesiumError> {
+    let (_, in_blocks) = gif_blocks(in_file)?;
+    let (header_end, out_blocks) = gif_blocks(out_file)?;
+
+    let mut frame = 0;
+    let mut extensions = vec![];
+    for (label, block) in in_blocks {
+        match label {
+            IMAGE_SEPARATOR => frame += 1,
+            COMMENT_LABEL | APPLICATION_LABEL => extensions.push((frame, block)),
+            _ => {}
+        }
+    }
+    let mut extensions = extensions.into_iter().peekable();
+
+    let mut result = Vec::with_capacity(out_file.len());
+    result.extend_from_slice(&out_file[..header_end]);
+    let mut frame = 0;
+    for (label, block) in out_blocks {
+        if label == COMMENT_LABEL || label == APPLICATION_LABEL {
+            continue;
+        }
+        while let Some((_, extension)) = extensions.next_if(|(f, _)| *f <= frame || label == TRAILER) {
+            result.extend_from_slice(&in_file[extension]);
+        }
+        result.extend_from_slice(&out_file[block]);
+        if label == IMAGE_SEPARATOR {
+            frame += 1;
+        }
+    }
+
+    Ok(result)
+}
+
+const EXTENSION_INTRODUCER: u8 = 0x21;
+const IMAGE_SEPARATOR: u8 = 0x2C;
+const TRAILER: u8 = 0x3B;
+const COMMENT_LABEL: u8 = 0xFE;
+const APPLICATION_LABEL: u8 = 0xFF;
+const GRAPHIC_CONTROL_LABEL: u8 = 0xF9;
+
+fn is_loop_extension(application: &[u8]) -> bool {
+    application == b"NETSCAPE2.0" || application == b"ANIMEXTS1.0"
+}
+
+/// Extension label, image separator or trailer of a block, and where it is in the file.
+type GifBlock = (u8, Range<usize>);
+
+/// Splits a GIF into its blocks, after the header, logical screen descriptor and global color table.
+///
+/// Returns where the blocks start, and every block.
+fn gif_blocks(data: &[u8]) -> Result<(usize, Vec<GifBlock>), CaesiumError> {
+    let malformed = || CaesiumError {
+        message: "Malformed GIF".into(),
+        code: 20414,
+    };
+    let color_table_size = |flags: u8| {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 0x07) + 1)
+        } else {
+            0
+        }
+    };
+    // Skips data sub-blocks up to and including the block terminator
+    let skip_sub_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *data.get(pos)? as usize;
+            pos += len + 1;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    };
+
+    let header_end = 13 + color_table_size(*data.get(10).ok_or_else(malformed)?);
+    let mut blocks = vec![];
+    let mut pos = header_end;
+    loop {
+        let start = pos;
+        let kind = *data.get(pos).ok_or_else(malformed)?;
+        let label = match kind {
+            EXTENSION_INTRODUCER => {
+                let label = *data.get(pos + 1).ok_or_else(malformed)?;
+                pos = skip_sub_blocks(pos + 2).ok_or_else(malformed)?;
+                label
+            }
+            IMAGE_SEPARATOR => {
+                let flags = *data.get(pos + 9).ok_or_else(malformed)?;
+                pos = skip_sub_blocks(pos + 10 + color_table_size(flags) + 1).ok_or_else(malformed)?;
+                kind
+            }
+            TRAILER => {
+                blocks.push((kind, pos..pos + 1));
+                break;
+            }
+            _ => return Err(malformed()),
+        };
+        if pos > data.len() {
+            return Err(malformed());
+        }
+        blocks.push((label, start..pos));
+    }
+
+    Ok((header_end, blocks))
+}
+
+/// Iterates over the payload of data sub-blocks, up to the block terminator.
+fn sub_blocks(mut data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let (&len, rest) = data.split_first()?;
+        if len == 0 || rest.len() < len as usize {
+            return None;
+        }
+        let (block, rest) = rest.split_at(len as usize);
+        data = rest;
+        Some(block)
+    })
+}
+
 fn lossy(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
     let mut decoder = gif::DecodeOptions::new();
     decoder.set_color_output(gif::ColorOutput::Indexed);
@@ -43,6 +239,7 @@ fn lossy(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, Caesiu
     let mut screen = gif_dispose::Screen::new_decoder(&decoder);
     let mut settings = Settings {
         quality: parameters.gif.quality as u8,
+        fast: parameters.gif.fast,
         repeat: decoder.repeat(),
         ..Default::default()
     };
@@ -56,63 +253,569 @@ fn lossy(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, Caesiu
     settings.width = Some(new_w);
     settings.height = Some(new_h);
 
-    let (collector, writer) = gifski::new(settings).map_err(|e| CaesiumError {
+    let delays = frame_delays(in_file, parameters.gif.preserve_delays)?;
+    let (timestamps, last_delay_ms) = frame_timestamps(&delays, parameters);
+    if timestamps.is_empty() {
+        return Err(CaesiumError {
+            message: "No GIF frames in the selected time range".into(),
+            code: 20413,
+        });
+    }
+    let retimed = parameters.gif.max_frame_rate > 0
+        || parameters.gif.max_frames > 0
+        || parameters.gif.trim_start.is_some()
+        || parameters.gif.trim_end.is_some();
+    // gifski plays the last frame for as long as the first timestamp is offset by
+    let first_timestamp_ms = if retimed { last_delay_ms } else { 0 };
+    // gifski writes delays of at least 20 ms, so exact delays are set once it is done with frames a slot apart
+    let exact_delays_ms: Option<Vec<u32>> = parameters.gif.preserve_delays.then(|| {
+        let shown: Vec<u32> = timestamps.iter().flatten().copied().collect();
+        shown.windows(2).map(|w| w[1] - w[0]).chain([last_delay_ms]).collect()
+    });
+    let preserve_delays = exact_delays_ms.is_some();
+
+    let compressed = write(settings, parameters, move |collector| {
+        let mut i = 0;
+        let mut timestamps = timestamps.into_iter();
+        while let Some(frame) = decoder.read_next_frame().map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20406,
+        })? {
+            screen.blit_frame(frame).map_err(|e| CaesiumError {
+                message: e.to_string(),
+                code: 20407,
+            })?;
+            let timestamp_ms = match timestamps.next() {
+                Some(Some(_)) if preserve_delays => (i as u32 + 1) * DELAY_SLOT_MS,
+                Some(Some(timestamp_ms)) => first_timestamp_ms + timestamp_ms,
+                Some(None) => continue,
+                None => break,
+            };
+            let mut pixels = screen.pixels_rgba().map_buf(|b| b.to_owned());
+
+            if new_w != old_w || new_h != old_h {
+                let mut raw_buf = Vec::with_capacity((old_w * old_h * 4) as usize);
+                for px in pixels.pixels() {
+                    raw_buf.extend_from_slice(&[px.r, px.g, px.b, px.a]);
+                }
+
+                let img = image::RgbaImage::from_raw(old_w, old_h, raw_buf).unwrap();
+                let resized = image::imageops::resize(&img, new_w, new_h, image::imageops::FilterType::Lanczos3);
+
+                pixels = to_gifski_image(&resized);
+            }
+
+            collector
+                .add_frame_rgba(i, pixels, timestamp_ms as f64 / 1000.0)
+                .map_err(|e| CaesiumError {
+                    message: e.to_string(),
+                    code: 20408,
+                })?;
+            i += 1;
+        }
+        Ok(())
+    })?;
+
+    match exact_delays_ms {
+        Some(delays_ms) => set_frame_delays(compressed, &delays_ms),
+        None => Ok(compressed),
+    }
+}
+
+/// Spacing of the frames given to gifski when the exact delays are set afterwards.
+const DELAY_SLOT_MS: u32 = 100;
+
+/// Sets the delays of a GIF written by gifski with frames [`DELAY_SLOT_MS`] apart.
+///
+/// gifski merges identical frames into one as long as all of them, so each frame takes the sum of the
+/// `delays_ms` it covers. The sums are rounded to centiseconds from the start, so rounding does not drift.
+fn set_frame_delays(mut out_file: Vec<u8>, delays_ms: &[u32]) -> Result<Vec<u8>, CaesiumError> {
+    let (_, blocks) = gif_blocks(&out_file)?;
+    let mut delays_ms = delays_ms.iter();
+    let mut time_ms = 0;
+    let mut time_cs = 0;
+    for (label, block) in blocks {
+        if label != GRAPHIC_CONTROL_LABEL || block.len() != 8 {
+            continue;
+        }
+        // Extension introducer, label, block size, flags, then the delay
+        let delay = block.start + 4..block.start + 6;
+        let slots = u16::from_le_bytes([out_file[delay.start], out_file[delay.start + 1]]) as u32;
+        let slots = ((slots * 10 + DELAY_SLOT_MS / 2) / DELAY_SLOT_MS).max(1);
+        time_ms += delays_ms.by_ref().take(slots as usize).sum::<u32>();
+        let end_cs = (time_ms + 5) / 10;
+        let delay_cs = (end_cs - time_cs).min(u16::MAX as u32) as u16;
+        time_cs = end_cs;
+        out_file[delay].copy_from_slice(&delay_cs.to_le_bytes());
+    }
+
+    Ok(out_file)
+}
+
+/// Works out when each frame starts in the output, in ms, applying the time range and the frame rate and
+/// count limits. Skipped frames are `None`, and the list stops at the last frame shown.
+///
+/// Dropped frames still get composited, so the next frame shown carries their changes, and the frame
+/// before them is held for longer to keep the total duration. Also returns how long the last frame lasts.
+fn frame_timestamps(delays: &[u32], parameters: &CSParameters) -> (Vec<Option<u32>>, u32) {
+    let total_ms: u32 = delays.iter().sum();
+    let start_ms = parameters
+        .gif
+        .trim_start
+        .map_or(0, |t| t.as_millis().min(total_ms as u128) as u32);
+    let end_ms = parameters.gif.trim_end.map_or(total_ms, |t| {
+        t.as_millis().clamp(start_ms as u128, total_ms as u128) as u32
+    });
+
+    let mut interval_ms = 0.0;
+    if parameters.gif.max_frame_rate > 0 {
+        interval_ms = 1000.0 / parameters.gif.max_frame_rate as f64;
+    }
+    if parameters.gif.max_frames > 0 {
+        interval_ms = f64::max(
+            interval_ms,
+            (end_ms - start_ms) as f64 / parameters.gif.max_frames as f64,
+        );
+    }
+
+    let mut timestamps = vec![];
+    let mut shown = 0;
+    let mut last_timestamp_ms = 0;
+    let mut next_slot_ms = 0.0;
+    let mut time_ms = 0;
+    for delay_ms in delays {
+        let (from_ms, to_ms) = (time_ms, time_ms + delay_ms);
+        time_ms = to_ms;
+        // Frames with no delay are kept from the start of the range up to its end, included
+        let outside = if from_ms == to_ms {
+            from_ms < start_ms || from_ms > end_ms
+        } else {
+            to_ms <= start_ms || from_ms >= end_ms
+        };
+        if outside {
+            timestamps.push(None);
+            continue;
+        }
+
+        let timestamp_ms = from_ms.max(start_ms) - start_ms;
+        if interval_ms > 0.0 {
+            if (timestamp_ms as f64) < next_slot_ms {
+                timestamps.push(None);
+                continue;
+            }
+            next_slot_ms = ((timestamp_ms as f64 / interval_ms).floor() + 1.0) * interval_ms;
+        }
+        timestamps.push(Some(timestamp_ms));
+        shown = timestamps.len();
+        last_timestamp_ms = timestamp_ms;
+    }
+    timestamps.truncate(shown);
+
+    (timestamps, end_ms - start_ms - last_timestamp_ms)
+}
+
+/// Rewrites the GIF without changing any displayed pixel.
+///
+/// Frames are composited, identical consecutive frames merged, and each one is cropped to the area that
+/// changed, with unchanged pixels left transparent. Colors are kept in the order of the original palettes,
+/// minus the ones no longer used. The input is returned as is when the result is not smaller.
+fn lossless(in_file: &Vec<u8>) -> Result<Vec<u8>, CaesiumError> {
+    let mut decoder = gif::DecodeOptions::new();
+    decoder.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = decoder.read_info(in_file.as_slice()).map_err(|e| CaesiumError {
         message: e.to_string(),
-        code: 20405,
+        code: 20404,
     })?;
+    let mut screen = gif_dispose::Screen::new_decoder(&decoder);
+    let width = decoder.width();
+    let height = decoder.height();
+    let repeat = decoder.repeat();
+    let mut source_colors: Vec<[u8; 3]> = decoder
+        .global_palette()
+        .unwrap_or_default()
+        .chunks_exact(3)
+        .map(|c| [c[0], c[1], c[2]])
+        .collect();
+
+    let mut frames: Vec<(Vec<RGBA8>, u16)> = vec![];
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20406,
+    })? {
+        if let Some(palette) = &frame.palette {
+            source_colors.extend(palette.chunks_exact(3).map(|c| [c[0], c[1], c[2]]));
+        }
+        screen.blit_frame(frame).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20407,
+        })?;
+        let pixels: Vec<RGBA8> = screen
+            .pixels_rgba()
+            .pixels()
+            .map(|px| if px.a == 0 { RGBA8::default() } else { px })
+            .collect();
+
+        match frames.last_mut() {
+            Some((last, delay)) if *last == pixels => *delay = delay.saturating_add(frame.delay),
+            _ => frames.push((pixels, frame.delay)),
+        }
+    }
+
+    let color_order: HashMap<[u8; 3], usize> = source_colors
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(i, color)| (*color, i))
+        .collect();
+    let blank = vec![RGBA8::default(); width as usize * height as usize];
+    // A pixel going back to transparent can only be done by disposing the previous frame to the background
+    let clears: Vec<bool> = (0..frames.len())
+        .map(|i| {
+            i > 0
+                && frames[i - 1]
+                    .0
+                    .iter()
+                    .zip(&frames[i].0)
+                    .any(|(previous, current)| previous.a != 0 && current.a == 0)
+        })
+        .collect();
+
+    let mut optimized_frames = Vec::with_capacity(frames.len());
+    let mut used_colors = HashSet::new();
+    for (i, (pixels, delay)) in frames.iter().enumerate() {
+        let base = if i == 0 || clears[i] { &blank } else { &frames[i - 1].0 };
+        let dispose_to_background = clears.get(i + 1).copied().unwrap_or(false);
+        let mut rect = bounding_rect(width, height, |p| pixels[p] != base[p]);
+        if dispose_to_background {
+            rect = union_rect(rect, bounding_rect(width, height, |p| pixels[p].a != 0));
+        }
+        let (left, top, rect_width, rect_height) = rect.unwrap_or((0, 0, 1, 1));
+
+        // None is left transparent, showing what is already on the canvas. Unchanged pixels can either be
+        // left transparent or drawn again, whichever compresses better
+        let mut changed = Vec::with_capacity(rect_width as usize * rect_height as usize);
+        let mut drawn = Vec::with_capacity(rect_width as usize * rect_height as usize);
+        for y in top..top + rect_height {
+            for x in left..left + rect_width {
+                let p = y as usize * width as usize + x as usize;
+                if pixels[p].a == 0 {
+                    changed.push(None);
+                    drawn.push(None);
+                } else {
+                    let color = [pixels[p].r, pixels[p].g, pixels[p].b];
+                    used_colors.insert(color);
+                    changed.push((pixels[p] != base[p]).then_some(color));
+                    drawn.push(Some(color));
+                }
+            }
+        }
+
+        let frame = gif::Frame {
+            delay: *delay,
+            dispose: if dispose_to_background {
+                gif::DisposalMethod::Background
+            } else {
+                gif::DisposalMethod::Keep
+            },
+            left,
+            top,
+            width: rect_width,
+            height: rect_height,
+            ..Default::default()
+        };
+        optimized_frames.push((frame, [changed, drawn]));
+    }
+
+    // One palette for every frame when the colors fit next to a transparent entry, otherwise one per frame
+    let global_palette = if used_colors.len() < 256 {
+        Some(sorted_palette(used_colors, &color_order))
+    } else {
+        None
+    };
 
     let mut result = vec![];
+    {
+        let mut encoder = gif::Encoder::new(
+            &mut result,
+            width,
+            height,
+            &global_palette
+                .as_ref()
+                .map(|p| palette_bytes(p, true))
+                .unwrap_or_default(),
+        )
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20411,
+        })?;
+        encoder.set_repeat(repeat).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20411,
+        })?;
 
-    std::thread::scope(|t| -> Result<(), CaesiumError> {
-        let frames_thread = t.spawn(move || -> Result<(), CaesiumError> {
-            let mut i = 0;
-            let mut total_delay_in_s = 0.0;
-            while let Some(frame) = decoder.read_next_frame().map_err(|e| CaesiumError {
+        for (frame, variants) in optimized_frames {
+            let mut best: Option<(usize, gif::Frame)> = None;
+            for colors in variants {
+                let Some(candidate) = indexed_frame(&frame, &colors, global_palette.as_deref(), &color_order) else {
+                    continue;
+                };
+                let size = lzw_size(&candidate);
+                if best.as_ref().is_none_or(|(best_size, _)| size < *best_size) {
+                    best = Some((size, candidate));
+                }
+            }
+            let Some((_, frame)) = best else {
+                return Ok(in_file.clone());
+            };
+
+            encoder.write_frame(&frame).map_err(|e| CaesiumError {
                 message: e.to_string(),
-                code: 20406,
-            })? {
-                screen.blit_frame(frame).map_err(|e| CaesiumError {
+                code: 20412,
+            })?;
+        }
+    }
+
+    if result.len() >= in_file.len() {
+        return Ok(in_file.clone());
+    }
+
+    Ok(result)
+}
+
+/// Maps the colors of a frame to palette indexes, with a local palette when there is no global one.
+///
+/// Returns `None` when the colors do not fit in a local palette.
+fn indexed_frame(
+    frame: &gif::Frame<'static>,
+    colors: &[Option<[u8; 3]>],
+    global_palette: Option<&[[u8; 3]]>,
+    color_order: &HashMap<[u8; 3], usize>,
+) -> Option<gif::Frame<'static>> {
+    let mut frame = frame.clone();
+    let has_transparency = colors.contains(&None);
+    let local_palette;
+    let palette = match global_palette {
+        Some(palette) => palette,
+        None => {
+            local_palette = sorted_palette(colors.iter().flatten().copied().collect(), color_order);
+            if local_palette.len() + has_transparency as usize > 256 {
+                return None;
+            }
+            frame.palette = Some(palette_bytes(&local_palette, has_transparency));
+            &local_palette
+        }
+    };
+
+    let indexes: HashMap<[u8; 3], u8> = palette.iter().enumerate().map(|(i, c)| (*c, i as u8)).collect();
+    let transparent = palette.len() as u8;
+    if has_transparency {
+        frame.transparent = Some(transparent);
+    }
+    frame.buffer = Cow::Owned(
+        colors
+            .iter()
+            .map(|color| color.map_or(transparent, |c| indexes[&c]))
+            .collect(),
+    );
+
+    Some(frame)
+}
+
+/// Size of the LZW compressed frame data, palette and headers aside.
+fn lzw_size(frame: &gif::Frame) -> usize {
+    let mut frame = frame.clone();
+    frame.palette = None;
+    let mut buffer = vec![];
+    let written = gif::Encoder::new(&mut buffer, frame.width, frame.height, &[0; 768])
+        .and_then(|mut encoder| encoder.write_frame(&frame));
+
+    match written {
+        Ok(()) => buffer.len(),
+        Err(_) => usize::MAX,
+    }
+}
+
+/// Orders colors as they first appear in the source palettes.
+fn sorted_palette(colors: HashSet<[u8; 3]>, color_order: &HashMap<[u8; 3], usize>) -> Vec<[u8; 3]> {
+    let mut palette: Vec<[u8; 3]> = colors.into_iter().collect();
+    palette.sort_by_key(|c| (color_order.get(c).copied().unwrap_or(usize::MAX), *c));
+    palette
+}
+
+/// Flattens a palette, optionally with a trailing entry to be used as the transparent index.
+fn palette_bytes(palette: &[[u8; 3]], with_transparent: bool) -> Vec<u8> {
+    let mut bytes: Vec<u8> = palette.iter().flatten().copied().collect();
+    if with_transparent {
+        bytes.extend_from_slice(&[0, 0, 0]);
+    }
+    bytes
+}
+
+/// Returns the `(left, top, width, height)` rectangle containing every pixel matching `f`.
+fn bounding_rect(width: u16, height: u16, f: impl Fn(usize) -> bool) -> Option<(u16, u16, u16, u16)> {
+    let mut rect: Option<(u16, u16, u16, u16)> = None;
+    for y in 0..height {
+        for x in 0..width {
+            if f(y as usize * width as usize + x as usize) {
+                rect = Some(match rect {
+                    Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                    None => (x, y, x, y),
+                });
+            }
+        }
+    }
+
+    rect.map(|(x0, y0, x1, y1)| (x0, y0, x1 - x0 + 1, y1 - y0 + 1))
+}
+
+fn union_rect(a: Option<(u16, u16, u16, u16)>, b: Option<(u16, u16, u16, u16)>) -> Option<(u16, u16, u16, u16)> {
+    match (a, b) {
+        (Some((ax, ay, aw, ah)), Some((bx, by, bw, bh))) => {
+            let (left, top) = (ax.min(bx), ay.min(by));
+            let right = (ax + aw).max(bx + bw);
+            let bottom = (ay + ah).max(by + bh);
+            Some((left, top, right - left, bottom - top))
+        }
+        (a, b) => a.or(b),
+    }
+}
+
+/// Reads how long each frame is displayed, without decoding the frames.
+///
+/// Unless preserving delays, frames shorter than 20 ms are played at 100 ms like most viewers do.
+fn frame_delays(in_file: &[u8], preserve_delays: bool) -> Result<Vec<u32>, CaesiumError> {
+    let mut decoder = gif::DecodeOptions::new();
+    decoder.skip_frame_decoding(true);
+    let mut decoder = decoder.read_info(in_file).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20404,
+    })?;
+
+    let mut delays = vec![];
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20406,
+    })? {
+        delays.push(frame.delay);
+    }
+
+    Ok(delays
+        .into_iter()
+        .map(|delay| {
+            if preserve_delays {
+                delay as u32 * 10
+            } else {
+                frame_delay_ms(delay)
+            }
+        })
+        .collect())
+}
+
+/// Decodes every frame of a GIF, composited on the full canvas.
+pub fn decode_animation(in_file: &[u8]) -> Result<Animation, CaesiumError> {
+    let mut decoder = gif::DecodeOptions::new();
+    decoder.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = decoder.read_info(in_file).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20404,
+    })?;
+    let mut screen = gif_dispose::Screen::new_decoder(&decoder);
+    let width = decoder.width() as u32;
+    let height = decoder.height() as u32;
+    // The NETSCAPE loop count is the number of repeats after the first play
+    let loop_count = match decoder.repeat() {
+        Repeat::Infinite => 0,
+        Repeat::Finite(n) => n as u32 + 1,
+    };
+
+    let mut frames = vec![];
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20406,
+    })? {
+        screen.blit_frame(frame).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20407,
+        })?;
+        let mut raw_buf = Vec::with_capacity((width * height * 4) as usize);
+        for px in screen.pixels_rgba().pixels() {
+            raw_buf.extend_from_slice(&[px.r, px.g, px.b, px.a]);
+        }
+        frames.push(AnimationFrame {
+            image: image::RgbaImage::from_raw(width, height, raw_buf).unwrap(),
+            delay_ms: frame_delay_ms(frame.delay),
+        });
+    }
+
+    Ok(Animation {
+        width,
+        height,
+        loop_count,
+        background: None,
+        frames,
+    })
+}
+
+pub fn encode_animation(animation: &Animation, parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let settings = Settings {
+        quality: parameters.gif.quality as u8,
+        fast: parameters.gif.fast,
+        repeat: match animation.loop_count {
+            0 => Repeat::Infinite,
+            // `Finite(0)` writes no loop extension, so the animation is played once
+            n => Repeat::Finite((n - 1).min(u16::MAX as u32) as u16),
+        },
+        width: Some(animation.width),
+        height: Some(animation.height),
+    };
+
+    write(settings, parameters, |collector| {
+        // gifski plays the last frame for as long as the first timestamp is offset by
+        let mut timestamp_ms = animation.frames.last().map_or(0, |f| f.delay_ms);
+        for (i, frame) in animation.frames.iter().enumerate() {
+            collector
+                .add_frame_rgba(i, to_gifski_image(&frame.image), timestamp_ms as f64 / 1000.0)
+                .map_err(|e| CaesiumError {
                     message: e.to_string(),
-                    code: 20407,
+                    code: 20408,
                 })?;
-                let mut pixels = screen.pixels_rgba().map_buf(|b| b.to_owned());
+            timestamp_ms += frame.delay_ms;
+        }
+        Ok(())
+    })
+}
 
-                if new_w != old_w || new_h != old_h {
-                    let mut raw_buf = Vec::with_capacity((old_w * old_h * 4) as usize);
-                    for px in pixels.pixels() {
-                        raw_buf.extend_from_slice(&[px.r, px.g, px.b, px.a]);
-                    }
+/// Runs gifski, feeding the collector from another thread.
+fn write<F>(settings: Settings, parameters: &CSParameters, add_frames: F) -> Result<Vec<u8>, CaesiumError>
+where
+    F: FnOnce(&Collector) -> Result<(), CaesiumError> + Send,
+{
+    let (collector, mut writer) = gifski::new(settings).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20405,
+    })?;
 
-                    let img = image::RgbaImage::from_raw(old_w, old_h, raw_buf).unwrap();
-                    let resized = image::imageops::resize(&img, new_w, new_h, image::imageops::FilterType::Lanczos3);
-
-                    let mut new_buf = Vec::with_capacity((new_w * new_h) as usize);
-                    for chunk in resized.chunks_exact(4) {
-                        new_buf.push(gif_dispose::RGBA8 {
-                            r: chunk[0],
-                            g: chunk[1],
-                            b: chunk[2],
-                            a: chunk[3],
-                        });
-                    }
+    // gifski 1.34 only takes these through deprecated writer setters until they move to `Settings`, hence the pinned
+    // version in Cargo.toml. Lossy and motion quality follow `quality` when left at 0.
+    #[expect(
+        deprecated,
+        reason = "lossy quality, motion quality and extra effort are not in gifski::Settings"
+    )]
+    {
+        if parameters.gif.lossy_quality > 0 {
+            writer.set_lossy_quality(parameters.gif.lossy_quality as u8);
+        }
+        if parameters.gif.motion_quality > 0 {
+            writer.set_motion_quality(parameters.gif.motion_quality as u8);
+        }
+        writer.set_extra_effort(parameters.gif.extra_effort);
+    }
 
-                    pixels = imgref::Img::new(new_buf, new_w as usize, new_h as usize);
-                }
+    let mut result = vec![];
 
-                let mut delay = frame.delay;
-                if delay <= 1 {
-                    delay = 10;
-                }
-                let delay_in_s = delay as f64 * 10.0 / 1000.0;
-                collector
-                    .add_frame_rgba(i, pixels, total_delay_in_s)
-                    .map_err(|e| CaesiumError {
-                        message: e.to_string(),
-                        code: 20408,
-                    })?;
-                i += 1;
-                total_delay_in_s += delay_in_s;
-            }
+    std::thread::scope(|t| -> Result<(), CaesiumError> {
+        let frames_thread = t.spawn(move || -> Result<(), CaesiumError> {
+            add_frames(&collector)?;
             drop(collector);
             Ok(())
         });
@@ -134,3 +837,23 @@ fn lossy(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, Caesiu
 
     Ok(result)
 }
+
+fn to_gifski_image(image: &image::RgbaImage) -> ImgVec<RGBA8> {
+    let mut buf = Vec::with_capacity((image.width() * image.height()) as usize);
+    for chunk in image.chunks_exact(4) {
+        buf.push(RGBA8 {
+            r: chunk[0],
+            g: chunk[1],
+            b: chunk[2],
+            a: chunk[3],
+        });
+    }
+
+    ImgVec::new(buf, image.width() as usize, image.height() as usize)
+}
+
+fn frame_delay_ms(delay: u16) -> u32 {
+    // Most viewers play frames with no delay at 100 ms
+    let delay = if delay <= 1 { 10 } else { delay };
+    delay as u32 * 10
+}
@@ -1,8 +1,12 @@
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
+use std::time::Duration;
 
-use crate::parameters::ChromaSubsampling;
-use crate::parameters::TiffCompression::{Deflate, Lzw, Packbits, Uncompressed};
+use crate::parameters::TiffCompression::{CcittGroup3, CcittGroup4, Deflate, Jpeg, Lzw, Packbits, Uncompressed};
+use crate::parameters::{
+    ChromaSubsampling, PngHighBitDepth, PngInterlace, PngQualityFallback, TiffColorType, TiffPredictor, TiffThreshold,
+    WebPImageHint,
+};
 use crate::{
     compress, compress_in_memory, compress_to_size, compress_to_size_in_memory, convert, convert_in_memory, error,
     CSParameters, SupportedFileTypes, TiffDeflateLevel,
@@ -22,15 +26,69 @@ pub struct CCSParameters {
     pub jpeg_progressive: bool,
     pub jpeg_optimize: bool,
     pub jpeg_preserve_icc: bool,
+    pub jpeg_arithmetic_coding: bool,
+    pub jpeg_restart_interval: u32,
+    pub jpeg_restart_in_rows: u32,
+    pub jpeg_optimize_scans: bool,
     pub png_quality: u32,
     pub png_optimization_level: u32,
     pub png_force_zopfli: bool,
     pub png_optimize: bool,
+    pub png_min_quality: u32,
+    pub png_quality_fallback: u32,
+    pub png_dithering_level: f32,
+    pub png_speed: u32,
+    pub png_max_colors: u32,
+    pub png_posterization: u32,
+    pub png_last_index_transparent: bool,
+    pub png_optimize_lossy: bool,
+    pub png_high_bit_depth: u32,
+    pub png_reduce_bit_depth: bool,
+    pub png_reduce_color_type: bool,
+    pub png_reduce_palette: bool,
+    pub png_reduce_grayscale: bool,
+    pub png_reduce_alpha: bool,
+    pub png_interlace: u32,
+    pub png_timeout: u32,
+    pub png_zopfli_iterations: u32,
+    pub png_deflate_level: u32,
     pub gif_quality: u32,
+    pub gif_optimize: bool,
+    pub gif_lossy_quality: u32,
+    pub gif_motion_quality: u32,
+    pub gif_fast: bool,
+    pub gif_extra_effort: bool,
+    pub gif_preserve_delays: bool,
+    pub gif_max_frame_rate: u32,
+    pub gif_max_frames: u32,
+    pub gif_trim_start: u32,
+    pub gif_trim_end: u32,
+    pub gif_keep_extensions: bool,
     pub webp_quality: u32,
     pub webp_lossless: bool,
+    pub webp_method: u32,
+    pub webp_sns_strength: u32,
+    pub webp_filter_strength: u32,
+    pub webp_filter_sharpness: u32,
+    pub webp_segments: u32,
+    pub webp_preprocessing: u32,
+    pub webp_alpha_quality: u32,
+    pub webp_near_lossless: u32,
+    pub webp_exact: bool,
+    pub webp_pass: u32,
+    pub webp_image_hint: u32,
+    pub webp_target_size: u32,
+    pub webp_target_psnr: f32,
+    pub webp_kmin: u32,
+    pub webp_kmax: u32,
+    pub webp_allow_mixed: bool,
+    pub webp_minimize_size: bool,
     pub tiff_compression: u32,
     pub tiff_deflate_level: u32,
+    pub tiff_color_type: u32,
+    pub tiff_predictor: u32,
+    pub tiff_jpeg_quality: u32,
+    pub tiff_threshold: u32,
     pub width: u32,
     pub height: u32,
 }
@@ -192,14 +250,61 @@ fn c_set_parameters(params: CCSParameters) -> CSParameters {
     parameters.jpeg.progressive = params.jpeg_progressive;
     parameters.jpeg.optimize = params.jpeg_optimize;
     parameters.jpeg.preserve_icc = params.jpeg_preserve_icc;
+    parameters.jpeg.arithmetic_coding = params.jpeg_arithmetic_coding;
+    parameters.jpeg.restart_interval = params.jpeg_restart_interval;
+    parameters.jpeg.restart_in_rows = params.jpeg_restart_in_rows;
+    parameters.jpeg.optimize_scans = params.jpeg_optimize_scans;
     parameters.png.quality = params.png_quality;
     parameters.png.optimize = params.png_optimize;
     parameters.keep_metadata = params.keep_metadata;
     parameters.png.optimization_level = params.png_optimization_level as u8;
     parameters.png.force_zopfli = params.png_force_zopfli;
+    parameters.png.min_quality = params.png_min_quality;
+    parameters.png.dithering_level = params.png_dithering_level;
+    parameters.png.speed = params.png_speed;
+    parameters.png.max_colors = params.png_max_colors;
+    parameters.png.posterization = params.png_posterization.min(u8::MAX as u32) as u8;
+    parameters.png.last_index_transparent = params.png_last_index_transparent;
+    parameters.png.optimize_lossy = params.png_optimize_lossy;
+    parameters.png.reductions.bit_depth = params.png_reduce_bit_depth;
+    parameters.png.reductions.color_type = params.png_reduce_color_type;
+    parameters.png.reductions.palette = params.png_reduce_palette;
+    parameters.png.reductions.grayscale = params.png_reduce_grayscale;
+    parameters.png.reductions.alpha = params.png_reduce_alpha;
+    parameters.png.timeout = (params.png_timeout > 0).then(|| Duration::from_millis(params.png_timeout as u64));
+    parameters.png.zopfli_iterations = params.png_zopfli_iterations.min(u8::MAX as u32) as u8;
+    parameters.png.deflate_level = params.png_deflate_level.min(u8::MAX as u32) as u8;
     parameters.gif.quality = params.gif_quality;
+    parameters.gif.optimize = params.gif_optimize;
+    parameters.gif.lossy_quality = params.gif_lossy_quality;
+    parameters.gif.motion_quality = params.gif_motion_quality;
+    parameters.gif.fast = params.gif_fast;
+    parameters.gif.extra_effort = params.gif_extra_effort;
+    parameters.gif.preserve_delays = params.gif_preserve_delays;
+    parameters.gif.max_frame_rate = params.gif_max_frame_rate;
+    parameters.gif.max_frames = params.gif_max_frames;
+    parameters.gif.trim_start =
+        (params.gif_trim_start > 0).then(|| Duration::from_millis(params.gif_trim_start as u64));
+    parameters.gif.trim_end = (params.gif_trim_end > 0).then(|| Duration::from_millis(params.gif_trim_end as u64));
+    parameters.gif.keep_extensions = params.gif_keep_extensions;
     parameters.webp.quality = params.webp_quality;
     parameters.webp.lossless = params.webp_lossless;
+    parameters.webp.method = params.webp_method;
+    parameters.webp.sns_strength = params.webp_sns_strength;
+    parameters.webp.filter_strength = params.webp_filter_strength;
+    parameters.webp.filter_sharpness = params.webp_filter_sharpness;
+    parameters.webp.segments = params.webp_segments;
+    parameters.webp.preprocessing = params.webp_preprocessing;
+    parameters.webp.alpha_quality = params.webp_alpha_quality;
+    parameters.webp.near_lossless = params.webp_near_lossless;
+    parameters.webp.exact = params.webp_exact;
+    parameters.webp.pass = params.webp_pass;
+    parameters.webp.target_size = params.webp_target_size;
+    parameters.webp.target_psnr = params.webp_target_psnr;
+    parameters.webp.kmin = params.webp_kmin;
+    parameters.webp.kmax = params.webp_kmax;
+    parameters.webp.allow_mixed = params.webp_allow_mixed;
+    parameters.webp.minimize_size = params.webp_minimize_size;
     parameters.width = params.width;
     parameters.height = params.height;
 
@@ -211,10 +316,37 @@ fn c_set_parameters(params: CCSParameters) -> CSParameters {
         _ => ChromaSubsampling::Auto,
     };
 
+    parameters.png.quality_fallback = match params.png_quality_fallback {
+        0 => PngQualityFallback::Error,
+        _ => PngQualityFallback::Lossless,
+    };
+
+    parameters.png.high_bit_depth = match params.png_high_bit_depth {
+        0 => PngHighBitDepth::Error,
+        2 => PngHighBitDepth::Quantize,
+        _ => PngHighBitDepth::Lossless,
+    };
+
+    parameters.png.interlace = match params.png_interlace {
+        0 => PngInterlace::Keep,
+        1 => PngInterlace::Force,
+        _ => PngInterlace::Remove,
+    };
+
+    parameters.webp.image_hint = match params.webp_image_hint {
+        1 => WebPImageHint::Picture,
+        2 => WebPImageHint::Photo,
+        3 => WebPImageHint::Graph,
+        _ => WebPImageHint::Default,
+    };
+
     parameters.tiff.algorithm = match params.tiff_compression {
         1 => Lzw,
         2 => Deflate,
         3 => Packbits,
+        4 => Jpeg,
+        5 => CcittGroup3,
+        6 => CcittGroup4,
         _ => Uncompressed,
     };
 
@@ -224,6 +356,24 @@ fn c_set_parameters(params: CCSParameters) -> CSParameters {
         _ => TiffDeflateLevel::Best,
     };
 
+    parameters.tiff.color_type = match params.tiff_color_type {
+        1 => TiffColorType::Rgb8,
+        2 => TiffColorType::Rgba8,
+        3 => TiffColorType::Gray8,
+        _ => TiffColorType::Original,
+    };
+
+    parameters.tiff.predictor = match params.tiff_predictor {
+        2 => TiffPredictor::Horizontal,
+        _ => TiffPredictor::None,
+    };
+    parameters.tiff.jpeg_quality = params.tiff_jpeg_quality;
+
+    parameters.tiff.threshold = match params.tiff_threshold {
+        1 => TiffThreshold::Adaptive,
+        _ => TiffThreshold::Global,
+    };
+
     parameters
 }
 
@@ -1,5 +1,5 @@
 use crate::error::CaesiumError;
-use crate::parameters::ChromaSubsampling;
+use crate::parameters::{ChromaSubsampling, JpegScanScript};
 use crate::resize::resize;
 use crate::CSParameters;
 use bytes::Bytes;
@@ -118,9 +118,9 @@ unsafe fn lossless(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>,
     let mut buf = ptr::null_mut();
     let mut buf_size = 0;
     jpeg_mem_dest(&mut dst_info, &mut buf, &mut buf_size);
-    if !parameters.jpeg.progressive {
-        dst_info.scan_info = null();
-    }
+    let scan_info = to_scan_info(&parameters.jpeg.scan_script);
+    set_scans(parameters, &mut dst_info, &scan_info);
+    set_entropy_coding(parameters, &mut dst_info);
     jpeg_write_coefficients(&mut dst_info, dst_coef_arrays);
 
     if parameters.keep_metadata || parameters.jpeg.preserve_icc {
@@ -207,9 +207,9 @@ unsafe fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, Ca
     dst_info.optimize_coding = i32::from(true);
     jpeg_set_quality(&mut dst_info, parameters.jpeg.quality as i32, false as boolean);
 
-    if !parameters.jpeg.progressive {
-        dst_info.scan_info = null();
-    }
+    let scan_info = to_scan_info(&parameters.jpeg.scan_script);
+    set_scans(parameters, &mut dst_info, &scan_info);
+    set_entropy_coding(parameters, &mut dst_info);
 
     jpeg_start_compress(&mut dst_info, true as boolean);
 
@@ -237,6 +237,77 @@ unsafe fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, Ca
     Ok(result)
 }
 
+/// Encodes 8-bit grayscale or RGB pixels as a baseline JPEG without JFIF header, as stored in TIFF strips.
+/// Color is written as YCbCr with 2x2 chroma subsampling.
+pub fn encode_pixels(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    components: u8,
+    quality: u32,
+) -> Result<Vec<u8>, CaesiumError> {
+    unsafe {
+        catch_unwind(|| encode(pixels, width, height, components, quality)).unwrap_or_else(|_| {
+            Err(CaesiumError {
+                message: format!("Internal JPEG error: {}", JPEG_ERROR.load(Ordering::SeqCst)),
+                code: 20104,
+            })
+        })
+    }
+}
+
+unsafe fn encode(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    components: u8,
+    quality: u32,
+) -> Result<Vec<u8>, CaesiumError> {
+    let mut dst_info: jpeg_compress_struct = mem::zeroed();
+    let mut dst_err = mem::zeroed();
+
+    dst_info.common.err = jpeg_std_error(&mut dst_err);
+    (*dst_info.common.err).error_exit = Some(error_handler);
+    (*dst_info.common.err).output_message = Some(error_message_handler);
+
+    jpeg_create_compress(&mut dst_info);
+
+    let mut buf_size = 0;
+    let mut buf = mem::zeroed();
+    jpeg_mem_dest(&mut dst_info, &mut buf, &mut buf_size);
+
+    dst_info.image_width = width;
+    dst_info.image_height = height;
+    dst_info.in_color_space = if components == 1 { JCS_GRAYSCALE } else { JCS_RGB };
+    dst_info.input_components = components as c_int;
+    jpeg_set_defaults(&mut dst_info);
+
+    dst_info.write_JFIF_header = false as boolean;
+    dst_info.scan_info = null();
+    dst_info.num_scans = 0;
+    dst_info.dct_method = J_DCT_METHOD::JDCT_ISLOW;
+    dst_info.optimize_coding = i32::from(true);
+    jpeg_set_quality(&mut dst_info, quality as i32, false as boolean);
+
+    jpeg_start_compress(&mut dst_info, true as boolean);
+
+    let row_stride = width as usize * components as usize;
+    while dst_info.next_scanline < dst_info.image_height {
+        let offset = dst_info.next_scanline as usize * row_stride;
+        let jsamparray = [pixels[offset..].as_ptr()];
+        jpeg_write_scanlines(&mut dst_info, jsamparray.as_ptr(), 1);
+    }
+
+    jpeg_finish_compress(&mut dst_info);
+    jpeg_destroy_compress(&mut dst_info);
+
+    let result = std::slice::from_raw_parts(buf, buf_size as usize).to_vec();
+
+    free(buf as *mut c_void);
+
+    Ok(result)
+}
+
 unsafe fn save_markers(src_info: &mut jpeg_decompress_struct, parameters: &CSParameters) {
     if parameters.keep_metadata {
         jpeg_save_markers(src_info, 0xFE, 0xFFFF);
@@ -297,6 +368,45 @@ unsafe fn write_metadata(src_info: &mut jpeg_decompress_struct, dst_info: &mut j
     }
 }
 
+fn to_scan_info(script: &JpegScanScript) -> Vec<jpeg_scan_info> {
+    script
+        .scans()
+        .iter()
+        .map(|scan| jpeg_scan_info {
+            comps_in_scan: scan.comps_in_scan as c_int,
+            component_index: scan.component_index.map(|c| c as c_int),
+            Ss: scan.ss as c_int,
+            Se: scan.se as c_int,
+            Ah: scan.ah as c_int,
+            Al: scan.al as c_int,
+        })
+        .collect()
+}
+
+// `scan_info` must outlive the compression, libjpeg only keeps the pointer
+unsafe fn set_scans(parameters: &CSParameters, dst_info: &mut jpeg_compress_struct, scan_info: &[jpeg_scan_info]) {
+    if !scan_info.is_empty() {
+        jpeg_c_set_bool_param(dst_info, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, false as boolean);
+        dst_info.scan_info = scan_info.as_ptr();
+        dst_info.num_scans = scan_info.len() as c_int;
+    } else if !parameters.jpeg.progressive {
+        dst_info.scan_info = null();
+    } else if !parameters.jpeg.optimize_scans {
+        jpeg_c_set_bool_param(dst_info, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, false as boolean);
+        // The default script was generated for the scan search, build the standard one instead
+        jpeg_simple_progression(dst_info);
+    }
+}
+
+fn set_entropy_coding(parameters: &CSParameters, dst_info: &mut jpeg_compress_struct) {
+    if parameters.jpeg.arithmetic_coding {
+        dst_info.arith_code = true as boolean;
+        dst_info.optimize_coding = false as boolean;
+    }
+    dst_info.restart_interval = parameters.jpeg.restart_interval;
+    dst_info.restart_in_rows = parameters.jpeg.restart_in_rows as c_int;
+}
+
 unsafe fn set_chroma_subsampling(subsampling: ChromaSubsampling, dst_info: &mut jpeg_compress_struct) {
     (*dst_info.comp_info.add(1)).h_samp_factor = 1;
     (*dst_info.comp_info.add(1)).v_samp_factor = 1;
@@ -6,10 +6,12 @@ use std::io::Write;
 
 #[cfg(feature = "tiff")]
 use crate::parameters::TiffCompression::{Deflate, Lzw, Packbits};
-use crate::parameters::{CSParameters, TiffDeflateLevel};
+use crate::parameters::{CSParameters, FrameSelection, TiffDeflateLevel};
 use crate::utils::{get_filetype_from_memory, get_filetype_from_path};
 use error::CaesiumError;
+pub use probe::{GifApplicationExtension, ImageInfo};
 
+mod animation;
 mod convert;
 pub mod error;
 #[cfg(feature = "gif")]
@@ -20,6 +22,7 @@ mod jpeg;
 pub mod parameters;
 #[cfg(feature = "png")]
 mod png;
+mod probe;
 mod resize;
 #[cfg(feature = "tiff")]
 mod tiff;
@@ -87,17 +90,25 @@ pub fn compress(input_path: String, output_path: String, parameters: &CSParamete
 pub fn compress_in_memory(in_file: Vec<u8>, parameters: &CSParameters) -> error::Result<Vec<u8>> {
     validate_parameters(parameters)?;
     let file_type = get_filetype_from_memory(in_file.as_slice());
+    compress_file_type(&in_file, file_type, parameters)
+}
+
+fn compress_file_type(
+    in_file: &Vec<u8>,
+    file_type: SupportedFileTypes,
+    parameters: &CSParameters,
+) -> error::Result<Vec<u8>> {
     let compressed_file = match file_type {
         #[cfg(feature = "jpg")]
-        SupportedFileTypes::Jpeg => jpeg::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::Jpeg => jpeg::compress_in_memory(in_file, parameters)?,
         #[cfg(feature = "png")]
-        SupportedFileTypes::Png => png::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::Png => png::compress_in_memory(in_file, parameters)?,
         #[cfg(feature = "gif")]
-        SupportedFileTypes::Gif => gif::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::Gif => gif::compress_in_memory(in_file, parameters)?,
         #[cfg(feature = "webp")]
-        SupportedFileTypes::WebP => webp::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::WebP => webp::compress_in_memory(in_file, parameters)?,
         #[cfg(feature = "tiff")]
-        SupportedFileTypes::Tiff => tiff::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::Tiff => tiff::compress_in_memory(in_file, parameters)?,
         _ => {
             return Err(CaesiumError {
                 message: "Format not supported for compression in memory".into(),
@@ -130,15 +141,14 @@ pub fn compress_to_size_in_memory(
     validate_parameters(parameters)?;
     let file_type = get_filetype_from_memory(&in_file);
 
-    let tolerance_percentage = 2;
-    let tolerance = max_output_size * tolerance_percentage / 100;
-    let mut quality = 80;
-    let mut last_less = 0;
-    let mut last_high = 101;
-    let max_tries: u32 = 10;
-    let mut tries: u32 = 0;
+    #[cfg(feature = "webp")]
+    if file_type == SupportedFileTypes::WebP {
+        if let Some(compressed_file) = webp::compress_to_size(&in_file, parameters, max_output_size)? {
+            return Ok(compressed_file);
+        }
+    }
 
-    let compressed_file = match file_type {
+    let set_quality: fn(&mut CSParameters, u32) = match file_type {
         #[cfg(feature = "tiff")]
         SupportedFileTypes::Tiff => {
             let algorithms = [Lzw, Packbits];
@@ -161,76 +171,102 @@ pub fn compress_to_size_in_memory(
                 })
             };
         }
-        _ => loop {
-            if tries >= max_tries {
-                return Err(CaesiumError {
-                    message: "Max tries reached".into(),
-                    code: 10201,
-                });
-            }
+        #[cfg(feature = "gif")]
+        SupportedFileTypes::Gif if !parameters.gif.optimize => {
+            let quality = parameters.gif.quality;
+            return search_size(
+                &in_file,
+                file_type,
+                parameters,
+                max_output_size,
+                return_smallest,
+                quality + 100,
+                |parameters, level| gif::set_size_level(parameters, quality, level),
+            );
+        }
+        #[cfg(feature = "jpg")]
+        SupportedFileTypes::Jpeg => |parameters, quality| parameters.jpeg.quality = quality,
+        #[cfg(feature = "png")]
+        SupportedFileTypes::Png => |parameters, quality| parameters.png.quality = quality,
+        #[cfg(feature = "gif")]
+        SupportedFileTypes::Gif => |parameters, quality| parameters.gif.quality = quality,
+        #[cfg(feature = "webp")]
+        SupportedFileTypes::WebP => |parameters, quality| parameters.webp.quality = quality,
+        _ => {
+            return Err(CaesiumError {
+                message: "Format not supported for compression to size".into(),
+                code: 10200,
+            });
+        }
+    };
 
-            let compressed_file = match file_type {
-                #[cfg(feature = "jpg")]
-                SupportedFileTypes::Jpeg => {
-                    parameters.jpeg.quality = quality;
-                    jpeg::compress_in_memory(&in_file, parameters)?
-                }
-                #[cfg(feature = "png")]
-                SupportedFileTypes::Png => {
-                    parameters.png.quality = quality;
-                    png::compress_in_memory(&in_file, parameters)?
-                }
-                #[cfg(feature = "gif")]
-                SupportedFileTypes::Gif => {
-                    parameters.gif.quality = quality;
-                    gif::compress_in_memory(&in_file, parameters)?
-                }
-                #[cfg(feature = "webp")]
-                SupportedFileTypes::WebP => {
-                    parameters.webp.quality = quality;
-                    webp::compress_in_memory(&in_file, parameters)?
-                }
-                _ => {
-                    return Err(CaesiumError {
-                        message: "Format not supported for compression to size".into(),
-                        code: 10200,
-                    });
-                }
-            };
+    search_size(
+        &in_file,
+        file_type,
+        parameters,
+        max_output_size,
+        return_smallest,
+        100,
+        set_quality,
+    )
+}
 
-            let compressed_file_size = compressed_file.len();
+/// Binary searches the highest level, from 1 to `levels`, whose output fits in `max_output_size`.
+///
+/// `set_level` applies a level to the parameters before each try. An output within 2% of `max_output_size` is
+/// accepted right away.
+fn search_size(
+    in_file: &Vec<u8>,
+    file_type: SupportedFileTypes,
+    parameters: &mut CSParameters,
+    max_output_size: usize,
+    return_smallest: bool,
+    levels: u32,
+    set_level: impl Fn(&mut CSParameters, u32),
+) -> error::Result<Vec<u8>> {
+    let tolerance_percentage = 2;
+    let tolerance = max_output_size * tolerance_percentage / 100;
+    let mut level = levels * 4 / 5;
+    let mut last_less = 0;
+    let mut last_high = levels + 1;
+    let max_tries: u32 = 10;
 
-            if compressed_file_size <= max_output_size && max_output_size - compressed_file_size < tolerance {
-                break compressed_file;
-            }
+    for _ in 0..max_tries {
+        set_level(parameters, level);
+        let compressed_file = compress_file_type(in_file, file_type, parameters)?;
+        let compressed_file_size = compressed_file.len();
 
-            if compressed_file_size <= max_output_size {
-                last_less = quality;
-            } else {
-                last_high = quality;
-            }
-            let last_quality = quality;
-            quality = ((last_high + last_less) / 2).clamp(1, 100);
-            if last_quality == quality {
-                if quality == 1 && last_high == 1 {
-                    return if return_smallest {
-                        Ok(compressed_file)
-                    } else {
-                        Err(CaesiumError {
-                            message: "Cannot compress to desired quality".into(),
-                            code: 10202,
-                        })
-                    };
-                }
+        if compressed_file_size <= max_output_size && max_output_size - compressed_file_size < tolerance {
+            return Ok(compressed_file);
+        }
 
-                break compressed_file;
+        if compressed_file_size <= max_output_size {
+            last_less = level;
+        } else {
+            last_high = level;
+        }
+        let last_level = level;
+        level = ((last_high + last_less) / 2).clamp(1, levels);
+        if last_level == level {
+            if level == 1 && last_high == 1 {
+                return if return_smallest {
+                    Ok(compressed_file)
+                } else {
+                    Err(CaesiumError {
+                        message: "Cannot compress to desired quality".into(),
+                        code: 10202,
+                    })
+                };
             }
 
-            tries += 1;
-        },
-    };
+            return Ok(compressed_file);
+        }
+    }
 
-    Ok(compressed_file)
+    Err(CaesiumError {
+        message: "Max tries reached".into(),
+        code: 10201,
+    })
 }
 
 /// Compresses an image file from the input path up to a specified size and writes the compressed image to the output path.
@@ -356,6 +392,213 @@ pub fn convert_in_memory(
     convert::convert_in_memory(in_file, format, parameters, compress_when_same_format)
 }
 
+/// Extracts a single frame of an animated GIF, WebP or PNG file and writes it as a still image in the specified format.
+///
+/// # Arguments
+///
+/// * `input_path` - A string representing the path to the input image file.
+/// * `output_path` - A string representing the path to the output still image file.
+/// * `parameters` - A reference to `CSParameters` containing conversion settings.
+/// * `format` - The format of the still image.
+/// * `frame` - Which frame to extract.
+///
+/// # Returns
+///
+/// * `Result<(), CaesiumError>` - Returns `Ok(())` if the extraction is successful, otherwise returns a `CaesiumError`.
+pub fn extract_frame(
+    input_path: String,
+    output_path: String,
+    parameters: &CSParameters,
+    format: SupportedFileTypes,
+    frame: FrameSelection,
+) -> error::Result<()> {
+    let in_file = fs::read(input_path).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10418,
+    })?;
+    let output_buffer = extract_frame_in_memory(in_file, parameters, format, frame)?;
+
+    let mut out_file = File::create(output_path).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10419,
+    })?;
+
+    out_file.write_all(&output_buffer).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10420,
+    })?;
+
+    Ok(())
+}
+
+/// Extracts a single frame of an animated GIF, WebP or PNG file in memory and returns it as a still image.
+///
+/// Frames are composited as they are displayed, honoring disposal and blending. A still input is converted
+/// as is when its first frame is requested.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input image file.
+/// * `parameters` - A reference to `CSParameters` containing conversion settings.
+/// * `format` - The format of the still image.
+/// * `frame` - Which frame to extract.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, CaesiumError>` - Returns a vector of bytes representing the still image if successful, otherwise returns a `CaesiumError`.
+pub fn extract_frame_in_memory(
+    in_file: Vec<u8>,
+    parameters: &CSParameters,
+    format: SupportedFileTypes,
+    frame: FrameSelection,
+) -> Result<Vec<u8>, CaesiumError> {
+    validate_parameters(parameters)?;
+    convert::extract_frame(in_file, frame, format, parameters)
+}
+
+/// Splits a multipage TIFF file into single page TIFF files, written to the output directory as `<name>_<page>.tif`.
+///
+/// # Arguments
+///
+/// * `input_path` - A string representing the path to the input TIFF file.
+/// * `output_directory` - A string representing the directory the pages are written to.
+/// * `parameters` - A reference to `CSParameters` containing compression settings.
+///
+/// # Returns
+///
+/// * `Result<Vec<String>, CaesiumError>` - Returns the paths of the written pages if successful, otherwise returns a `CaesiumError`.
+#[cfg(feature = "tiff")]
+pub fn split_tiff(
+    input_path: String,
+    output_directory: String,
+    parameters: &CSParameters,
+) -> error::Result<Vec<String>> {
+    let in_file = fs::read(&input_path).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10600,
+    })?;
+    let pages = split_tiff_in_memory(in_file, parameters)?;
+
+    let name = std::path::Path::new(&input_path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut output_paths = Vec::with_capacity(pages.len());
+    for (index, page) in pages.iter().enumerate() {
+        let output_path = std::path::Path::new(&output_directory).join(format!("{name}_{index}.tif"));
+        let mut out_file = File::create(&output_path).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 10601,
+        })?;
+        out_file.write_all(page).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 10602,
+        })?;
+        output_paths.push(output_path.to_string_lossy().into_owned());
+    }
+
+    Ok(output_paths)
+}
+
+/// Splits a multipage TIFF file in memory into single page TIFF files.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input TIFF file.
+/// * `parameters` - A reference to `CSParameters` containing compression settings.
+///
+/// # Returns
+///
+/// * `Result<Vec<Vec<u8>>, CaesiumError>` - Returns one TIFF file per page if successful, otherwise returns a `CaesiumError`.
+#[cfg(feature = "tiff")]
+pub fn split_tiff_in_memory(in_file: Vec<u8>, parameters: &CSParameters) -> error::Result<Vec<Vec<u8>>> {
+    validate_parameters(parameters)?;
+    tiff::split_in_memory(&in_file, parameters)
+}
+
+/// Assembles several image files into one multipage TIFF file and writes it to the output path.
+///
+/// # Arguments
+///
+/// * `input_paths` - The paths of the input image files, in page order. Every page of a TIFF input is kept.
+/// * `output_path` - A string representing the path to the output TIFF file.
+/// * `parameters` - A reference to `CSParameters` containing compression settings.
+///
+/// # Returns
+///
+/// * `Result<(), CaesiumError>` - Returns `Ok(())` if assembling is successful, otherwise returns a `CaesiumError`.
+#[cfg(feature = "tiff")]
+pub fn assemble_tiff(input_paths: Vec<String>, output_path: String, parameters: &CSParameters) -> error::Result<()> {
+    let images = input_paths
+        .iter()
+        .map(|path| {
+            fs::read(path).map_err(|e| CaesiumError {
+                message: e.to_string(),
+                code: 10600,
+            })
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+    let output_buffer = assemble_tiff_in_memory(images, parameters)?;
+
+    let mut out_file = File::create(output_path).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10601,
+    })?;
+
+    out_file.write_all(&output_buffer).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10602,
+    })?;
+
+    Ok(())
+}
+
+/// Assembles several image files in memory into one multipage TIFF file.
+///
+/// # Arguments
+///
+/// * `images` - The input image files, in page order. Every page of a TIFF input is kept.
+/// * `parameters` - A reference to `CSParameters` containing compression settings.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, CaesiumError>` - Returns a vector of bytes representing the multipage TIFF if successful, otherwise returns a `CaesiumError`.
+#[cfg(feature = "tiff")]
+pub fn assemble_tiff_in_memory(images: Vec<Vec<u8>>, parameters: &CSParameters) -> error::Result<Vec<u8>> {
+    validate_parameters(parameters)?;
+    tiff::assemble_in_memory(&images, parameters)
+}
+
+/// Reads the format, dimensions and textual metadata of an image file without compressing it.
+///
+/// # Arguments
+///
+/// * `input_path` - A string representing the path to the input image file.
+///
+/// # Returns
+///
+/// * `Result<ImageInfo, CaesiumError>` - Returns the information read from the image if successful, otherwise returns a `CaesiumError`.
+pub fn probe(input_path: String) -> error::Result<ImageInfo> {
+    let in_file = fs::read(input_path).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10502,
+    })?;
+    probe::probe_in_memory(&in_file)
+}
+
+/// Reads the format, dimensions and textual metadata of an image file in memory without compressing it.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input image file.
+///
+/// # Returns
+///
+/// * `Result<ImageInfo, CaesiumError>` - Returns the information read from the image if successful, otherwise returns a `CaesiumError`.
+pub fn probe_in_memory(in_file: Vec<u8>) -> error::Result<ImageInfo> {
+    probe::probe_in_memory(&in_file)
+}
+
 fn validate_parameters(parameters: &CSParameters) -> error::Result<()> {
     if parameters.jpeg.quality > 100 {
         return Err(CaesiumError {
@@ -364,6 +607,30 @@ fn validate_parameters(parameters: &CSParameters) -> error::Result<()> {
         });
     }
 
+    if parameters.jpeg.restart_interval > 65535 || parameters.jpeg.restart_in_rows > 65535 {
+        return Err(CaesiumError {
+            message: "Invalid JPEG restart interval".into(),
+            code: 10007,
+        });
+    }
+
+    if parameters.jpeg.scan_script.scans().iter().any(|scan| {
+        scan.comps_in_scan == 0
+            || scan.comps_in_scan > 4
+            || scan.component_index[..scan.comps_in_scan as usize]
+                .iter()
+                .any(|&c| c > 3)
+            || scan.ss > scan.se
+            || scan.se > 63
+            || scan.ah > 13
+            || scan.al > 13
+    }) {
+        return Err(CaesiumError {
+            message: "Invalid JPEG scan script".into(),
+            code: 10008,
+        });
+    }
+
     if parameters.png.quality > 100 {
         return Err(CaesiumError {
             message: "Invalid PNG quality value".into(),
@@ -378,6 +645,57 @@ fn validate_parameters(parameters: &CSParameters) -> error::Result<()> {
         });
     }
 
+    if parameters.png.min_quality > parameters.png.quality {
+        return Err(CaesiumError {
+            message: "Invalid PNG minimum quality value".into(),
+            code: 10009,
+        });
+    }
+
+    if parameters.png.speed < 1 || parameters.png.speed > 10 {
+        return Err(CaesiumError {
+            message: "Invalid PNG speed value".into(),
+            code: 10010,
+        });
+    }
+
+    if parameters.png.max_colors < 2 || parameters.png.max_colors > 256 {
+        return Err(CaesiumError {
+            message: "Invalid PNG max colors value".into(),
+            code: 10011,
+        });
+    }
+
+    if !(0.0..=1.0).contains(&parameters.png.dithering_level) {
+        return Err(CaesiumError {
+            message: "Invalid PNG dithering level".into(),
+            code: 10012,
+        });
+    }
+
+    if parameters.png.posterization > 4 {
+        return Err(CaesiumError {
+            message: "Invalid PNG posterization value".into(),
+            code: 10013,
+        });
+    }
+
+    if parameters.png.deflate_level > 12 {
+        return Err(CaesiumError {
+            message: "Invalid PNG deflate level".into(),
+            code: 10015,
+        });
+    }
+
+    if parameters.png.text_chunks.iter().any(|c| {
+        c.keyword.is_empty() || c.keyword.chars().count() > 79 || c.keyword.chars().any(|k| k == '\0' || k > '\u{ff}')
+    }) {
+        return Err(CaesiumError {
+            message: "Invalid PNG text chunk keyword".into(),
+            code: 10014,
+        });
+    }
+
     if parameters.gif.quality > 100 || parameters.gif.quality < 1 {
         return Err(CaesiumError {
             message: "Invalid GIF quality value".into(),
@@ -385,6 +703,22 @@ fn validate_parameters(parameters: &CSParameters) -> error::Result<()> {
         });
     }
 
+    if parameters.gif.lossy_quality > 100 || parameters.gif.motion_quality > 100 {
+        return Err(CaesiumError {
+            message: "Invalid GIF lossy or motion quality value".into(),
+            code: 10017,
+        });
+    }
+
+    if let (Some(start), Some(end)) = (parameters.gif.trim_start, parameters.gif.trim_end) {
+        if end <= start {
+            return Err(CaesiumError {
+                message: "Invalid GIF time range".into(),
+                code: 10018,
+            });
+        }
+    }
+
     if parameters.webp.quality > 100 {
         return Err(CaesiumError {
             message: "Invalid WebP quality value".into(),
@@ -392,6 +726,31 @@ fn validate_parameters(parameters: &CSParameters) -> error::Result<()> {
         });
     }
 
+    let webp = &parameters.webp;
+    if webp.method > 6
+        || webp.sns_strength > 100
+        || webp.filter_strength > 100
+        || webp.filter_sharpness > 7
+        || !(1..=4).contains(&webp.segments)
+        || webp.preprocessing > 2
+        || webp.alpha_quality > 100
+        || webp.near_lossless > 100
+        || !(1..=10).contains(&webp.pass)
+        || !(0.0..=99.0).contains(&webp.target_psnr)
+    {
+        return Err(CaesiumError {
+            message: "Invalid WebP encoding settings".into(),
+            code: 10016,
+        });
+    }
+
+    if parameters.tiff.jpeg_quality > 100 {
+        return Err(CaesiumError {
+            message: "Invalid TIFF JPEG quality value".into(),
+            code: 10019,
+        });
+    }
+
     Ok(())
 }
 
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use crate::parameters::TiffCompression::Deflate;
 
 /// Enum representing different chroma subsampling options for JPEG compression.
@@ -16,18 +18,189 @@ pub enum ChromaSubsampling {
     Auto,
 }
 
+/// Maximum number of scans a `JpegScanScript` can hold.
+pub const MAX_JPEG_SCANS: usize = 64;
+
+/// Struct representing a single scan of a JPEG scan script, mirroring libjpeg's `jpeg_scan_info`.
+///
+/// Fields:
+/// - `comps_in_scan`: Number of components encoded in this scan (1-4)
+/// - `component_index`: Indexes of the components encoded in this scan
+/// - `ss`: First DCT coefficient in the spectral selection (0-63)
+/// - `se`: Last DCT coefficient in the spectral selection (0-63)
+/// - `ah`: Successive approximation high bit position (0 on the first scan of a band)
+/// - `al`: Successive approximation low bit position
+#[derive(Copy, Clone, PartialEq, Default)]
+pub struct JpegScan {
+    pub comps_in_scan: u8,
+    pub component_index: [u8; 4],
+    pub ss: u8,
+    pub se: u8,
+    pub ah: u8,
+    pub al: u8,
+}
+
+/// Struct representing a custom progressive scan script for JPEG compression.
+///
+/// An empty script means that the default mozjpeg scans are used.
+#[derive(Copy, Clone)]
+pub struct JpegScanScript {
+    scans: [JpegScan; MAX_JPEG_SCANS],
+    len: usize,
+}
+
+impl Default for JpegScanScript {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JpegScanScript {
+    pub fn new() -> JpegScanScript {
+        JpegScanScript {
+            scans: [JpegScan::default(); MAX_JPEG_SCANS],
+            len: 0,
+        }
+    }
+
+    /// Appends a scan to the script. Returns `false` if the script is already full.
+    pub fn push(&mut self, scan: JpegScan) -> bool {
+        if self.len >= MAX_JPEG_SCANS {
+            return false;
+        }
+        self.scans[self.len] = scan;
+        self.len += 1;
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn scans(&self) -> &[JpegScan] {
+        &self.scans[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Enum representing what to do when lossy PNG compression cannot reach the minimum quality.
+///
+/// - `Error`: Fail with an error
+/// - `Lossless`: Fall back to lossless optimization
+#[derive(Copy, Clone, PartialEq)]
+pub enum PngQualityFallback {
+    Error,
+    Lossless,
+}
+
+/// Enum representing how lossy PNG compression handles inputs with more than 8 bits per channel.
+///
+/// - `Error`: Fail with an error
+/// - `Lossless`: Use lossless optimization instead, keeping the original precision
+/// - `Quantize`: Quantize to an 8-bit palette anyway
+#[derive(Copy, Clone, PartialEq)]
+pub enum PngHighBitDepth {
+    Error,
+    Lossless,
+    Quantize,
+}
+
+/// Enum representing the Adam7 interlacing of PNG output.
+///
+/// - `Keep`: Interlace the output only if the input is interlaced
+/// - `Force`: Always interlace the output, for progressive display on slow networks
+/// - `Remove`: Never interlace the output
+#[derive(Copy, Clone, PartialEq)]
+pub enum PngInterlace {
+    Keep,
+    Force,
+    Remove,
+}
+
+/// Enum representing which frame of an animation to extract as a still image.
+///
+/// - `Index`: Frame at the given position, starting from 0
+/// - `Time`: Frame displayed at the given time from the start of the animation
+/// - `Representative`: Frame closest to the average of all frames, which skips fades and blank title frames
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FrameSelection {
+    Index(usize),
+    Time(Duration),
+    Representative,
+}
+
+/// Struct representing which reductions oxipng is allowed to perform.
+///
+/// Fields:
+/// - `bit_depth`: Whether to reduce the bit depth
+/// - `color_type`: Whether to change the color type
+/// - `palette`: Whether to reduce or sort the palette
+/// - `grayscale`: Whether to convert to grayscale
+/// - `alpha`: Whether to alter the color of fully transparent pixels
+#[derive(Copy, Clone)]
+pub struct PngReductions {
+    pub bit_depth: bool,
+    pub color_type: bool,
+    pub palette: bool,
+    pub grayscale: bool,
+    pub alpha: bool,
+}
+
+/// Struct representing a PNG textual chunk.
+///
+/// Fields:
+/// - `keyword`: Keyword of the chunk, such as `Author` or `Software` (1-79 bytes)
+/// - `text`: Text of the chunk
+#[derive(Clone, PartialEq, Debug)]
+pub struct PngTextChunk {
+    pub keyword: String,
+    pub text: String,
+}
+
+impl PngTextChunk {
+    pub fn new(keyword: impl Into<String>, text: impl Into<String>) -> PngTextChunk {
+        PngTextChunk {
+            keyword: keyword.into(),
+            text: text.into(),
+        }
+    }
+}
+
+/// Enum representing the kind of content of a WebP image, used to tune lossless compression.
+///
+/// - `Default`: No specific content
+/// - `Picture`: Digital picture, like a portrait or an indoor shot
+/// - `Photo`: Outdoor photograph, with natural lighting
+/// - `Graph`: Discrete tone image, like a chart or a map
+#[derive(Copy, Clone, PartialEq)]
+pub enum WebPImageHint {
+    Default,
+    Picture,
+    Photo,
+    Graph,
+}
+
 /// Enum representing different compression algorithms for TIFF images.
 ///
 /// - `Uncompressed`: No compression
 /// - `Lzw`: LZW compression
 /// - `Deflate`: Deflate compression
 /// - `Packbits`: PackBits compression
+/// - `Jpeg`: Lossy JPEG compression, for 8-bit grayscale and RGB pages
+/// - `CcittGroup3`: CCITT T.4 (Group 3) fax compression, thresholding every page to 1-bit
+/// - `CcittGroup4`: CCITT T.6 (Group 4) fax compression, thresholding every page to 1-bit
 #[derive(Copy, Clone, PartialEq)]
 pub enum TiffCompression {
     Uncompressed = 0,
     Lzw = 1,
     Deflate = 2,
     Packbits = 3,
+    Jpeg = 4,
+    CcittGroup3 = 5,
+    CcittGroup4 = 6,
 }
 
 /// Enum representing different deflate levels for TIFF compression.
@@ -42,6 +215,40 @@ pub enum TiffDeflateLevel {
     Best = 9,
 }
 
+/// Enum representing the predictor applied to TIFF pages before LZW or Deflate compression.
+///
+/// - `None`: No prediction
+/// - `Horizontal`: Horizontal differencing, usually much smaller for photographic pages
+#[derive(Copy, Clone, PartialEq)]
+pub enum TiffPredictor {
+    None = 1,
+    Horizontal = 2,
+}
+
+/// Enum representing the color type of TIFF pages in the output.
+///
+/// - `Original`: Keep the color type and bit depth of every page
+/// - `Rgb8`: Convert every page to 8-bit RGB
+/// - `Rgba8`: Convert every page to 8-bit RGBA
+/// - `Gray8`: Convert every page to 8-bit grayscale
+#[derive(Copy, Clone, PartialEq)]
+pub enum TiffColorType {
+    Original = 0,
+    Rgb8 = 1,
+    Rgba8 = 2,
+    Gray8 = 3,
+}
+
+/// Enum representing how pages are thresholded to 1-bit for CCITT compression.
+///
+/// - `Global`: A single threshold for the whole page, chosen with Otsu's method
+/// - `Adaptive`: A threshold following the mean of the surrounding area, for unevenly lit scans
+#[derive(Copy, Clone, PartialEq)]
+pub enum TiffThreshold {
+    Global = 0,
+    Adaptive = 1,
+}
+
 /// Struct representing parameters for JPEG compression.
 ///
 /// Fields:
@@ -50,6 +257,11 @@ pub enum TiffDeflateLevel {
 /// - `progressive`: Whether to use progressive JPEG
 /// - `optimize`: Whether to use lossless optimization for JPEG
 /// - `preserve_icc`: Always keep the original ICC data regardless of other options
+/// - `arithmetic_coding`: Whether to use arithmetic coding instead of Huffman coding (not supported by every decoder)
+/// - `restart_interval`: Number of MCUs between restart markers (0 to disable)
+/// - `restart_in_rows`: Number of MCU rows between restart markers, overrides `restart_interval` (0 to disable)
+/// - `scan_script`: Custom scan script, overrides `progressive` and `optimize_scans` when not empty
+/// - `optimize_scans`: Whether to let mozjpeg search for the smallest progressive scan layout
 #[derive(Copy, Clone)]
 pub struct JpegParameters {
     pub quality: u32,
@@ -57,6 +269,11 @@ pub struct JpegParameters {
     pub progressive: bool,
     pub optimize: bool,
     pub preserve_icc: bool,
+    pub arithmetic_coding: bool,
+    pub restart_interval: u32,
+    pub restart_in_rows: u32,
+    pub scan_script: JpegScanScript,
+    pub optimize_scans: bool,
 }
 
 /// Struct representing parameters for PNG compression.
@@ -66,21 +283,77 @@ pub struct JpegParameters {
 /// - `force_zopfli`: Whether to force the use of Zopfli compression (can be very slow)
 /// - `optimization_level`: Optimization level for PNG compression (0-6)
 /// - `optimize`: Whether to use lossless optimization for PNG
-#[derive(Copy, Clone)]
+/// - `min_quality`: Minimum acceptable quality for lossy compression (0-100)
+/// - `quality_fallback`: What to do when `min_quality` cannot be reached
+/// - `dithering_level`: Dithering level for lossy compression (0.0-1.0)
+/// - `speed`: Quantization speed, lower is slower and better (1-10)
+/// - `max_colors`: Maximum number of palette colors (2-256)
+/// - `posterization`: Number of least significant bits to ignore, for lower precision displays (0-4)
+/// - `last_index_transparent`: Whether to put the transparent color at the end of the palette
+/// - `optimize_lossy`: Whether to also run the lossless optimization on the output of lossy compression
+/// - `high_bit_depth`: How lossy compression handles 16-bit inputs
+/// - `reductions`: Reductions allowed during lossless optimization
+/// - `interlace`: Adam7 interlacing of the output, `Force` does not apply to animated PNGs, which cannot be re-interlaced
+/// - `keep_text_keys`: Keywords of the text chunks to keep when `keep_metadata` is off (all are kept otherwise)
+/// - `text_chunks`: Text chunks to add to the output, replacing existing chunks with the same keyword
+/// - `timeout`: Maximum time spent trying lossless optimizations, the best result found so far is used after it
+/// - `zopfli_iterations`: Zopfli iterations when `force_zopfli` is on (0 for 15, or 5 on inputs over 2 MB)
+/// - `deflate_level`: libdeflater compression level (1-12, 0 for the default of the optimization level)
+#[derive(Clone)]
 pub struct PngParameters {
     pub quality: u32,
     pub force_zopfli: bool,
     pub optimization_level: u8,
     pub optimize: bool,
+    pub min_quality: u32,
+    pub quality_fallback: PngQualityFallback,
+    pub dithering_level: f32,
+    pub speed: u32,
+    pub max_colors: u32,
+    pub posterization: u8,
+    pub last_index_transparent: bool,
+    pub optimize_lossy: bool,
+    pub high_bit_depth: PngHighBitDepth,
+    pub reductions: PngReductions,
+    pub interlace: PngInterlace,
+    pub keep_text_keys: Vec<String>,
+    pub text_chunks: Vec<PngTextChunk>,
+    pub timeout: Option<Duration>,
+    pub zopfli_iterations: u8,
+    pub deflate_level: u8,
 }
 
 /// Struct representing parameters for GIF compression.
 ///
 /// Fields:
 /// - `quality`: Quality of the GIF image (0-100)
+/// - `optimize`: Whether to use lossless optimization for GIF, ignored when resizing
+/// - `lossy_quality`: Quality of the lossy LZW compression (1-100), 0 to follow `quality`
+/// - `motion_quality`: Quality of the temporal denoising between frames (1-100), 0 to follow `quality`
+/// - `fast`: Whether to trade quality for a faster encode
+/// - `extra_effort`: Whether to spend more time looking for a smaller file
+/// - `preserve_delays`: Whether to keep the original frame delays exactly, including 10 ms and 0 ms ones, instead of
+///   playing frames shorter than 20 ms at 100 ms
+/// - `max_frame_rate`: Maximum frames per second, frames in between are dropped (0 for no limit)
+/// - `max_frames`: Maximum number of frames, spread evenly over the animation (0 for no limit)
+/// - `trim_start`: Time at which the output animation starts, if any
+/// - `trim_end`: Time at which the output animation ends, if any
+/// - `keep_extensions`: Whether to keep the comment and application extensions (e.g. XMP) of the original GIF,
+///   along with its loop extension as is
 #[derive(Copy, Clone)]
 pub struct GifParameters {
     pub quality: u32,
+    pub optimize: bool,
+    pub lossy_quality: u32,
+    pub motion_quality: u32,
+    pub fast: bool,
+    pub extra_effort: bool,
+    pub preserve_delays: bool,
+    pub max_frame_rate: u32,
+    pub max_frames: u32,
+    pub trim_start: Option<Duration>,
+    pub trim_end: Option<Duration>,
+    pub keep_extensions: bool,
 }
 
 /// Struct representing parameters for WebP compression.
@@ -88,10 +361,44 @@ pub struct GifParameters {
 /// Fields:
 /// - `quality`: Quality of the WebP image (0-100)
 /// - `lossless`: Whether to use lossless compression for WebP
+/// - `method`: Compression method, higher is slower and better (0-6)
+/// - `sns_strength`: Spatial noise shaping strength (0-100)
+/// - `filter_strength`: Deblocking filter strength (0-100, 0 disables it)
+/// - `filter_sharpness`: Deblocking filter sharpness (0-7, 0 is the sharpest)
+/// - `segments`: Number of segments (1-4)
+/// - `preprocessing`: Preprocessing filter (0 none, 1 segment smoothing, 2 pseudo-random dithering)
+/// - `alpha_quality`: Quality of the alpha plane (0-100)
+/// - `near_lossless`: Near lossless preprocessing level for lossless compression (0-100, 100 disables it)
+/// - `exact`: Whether to preserve the RGB values under fully transparent pixels
+/// - `pass`: Number of entropy analysis passes (1-10, at least 6 with a target size or PSNR)
+/// - `image_hint`: Kind of content of the image
+/// - `target_size`: Size in bytes libwebp aims for, overriding `quality` (0 disables it)
+/// - `target_psnr`: Minimum distortion in dB libwebp aims for, overriding `quality` (0 disables it)
+/// - `kmin`: Minimum distance between key frames of animations, adjusted by libwebp to fit `kmax`
+/// - `kmax`: Maximum distance between key frames of animations (0 inserts none, 1 makes all frames key frames)
+/// - `allow_mixed`: Whether animation frames can be lossy or lossless, whichever is smaller
+/// - `minimize_size`: Whether to try harder to shrink animations, which is slower
 #[derive(Copy, Clone)]
 pub struct WebPParameters {
     pub quality: u32,
     pub lossless: bool,
+    pub method: u32,
+    pub sns_strength: u32,
+    pub filter_strength: u32,
+    pub filter_sharpness: u32,
+    pub segments: u32,
+    pub preprocessing: u32,
+    pub alpha_quality: u32,
+    pub near_lossless: u32,
+    pub exact: bool,
+    pub pass: u32,
+    pub image_hint: WebPImageHint,
+    pub target_size: u32,
+    pub target_psnr: f32,
+    pub kmin: u32,
+    pub kmax: u32,
+    pub allow_mixed: bool,
+    pub minimize_size: bool,
 }
 
 /// Struct representing parameters for TIFF compression.
@@ -99,10 +406,18 @@ pub struct WebPParameters {
 /// Fields:
 /// - `algorithm`: Compression algorithm for TIFF
 /// - `deflate_level`: Deflate level for TIFF compression
+/// - `color_type`: Color type of the output pages, converting them only when not `Original`
+/// - `predictor`: Predictor used with LZW and Deflate compression
+/// - `jpeg_quality`: Quality of JPEG compressed pages (0-100)
+/// - `threshold`: Thresholding used to convert pages to 1-bit for CCITT compression
 #[derive(Copy, Clone)]
 pub struct TiffParameters {
     pub algorithm: TiffCompression,
     pub deflate_level: TiffDeflateLevel,
+    pub color_type: TiffColorType,
+    pub predictor: TiffPredictor,
+    pub jpeg_quality: u32,
+    pub threshold: TiffThreshold,
 }
 
 /// Struct representing overall compression parameters.
@@ -116,7 +431,9 @@ pub struct TiffParameters {
 /// - `keep_metadata`: Whether to keep metadata in the compressed image
 /// - `width`: Width of the output image
 /// - `height`: Height of the output image
-#[derive(Copy, Clone)]
+///
+/// Since 0.21 the PNG text options own their strings, so the parameters are `Clone` but no longer `Copy`.
+#[derive(Clone)]
 pub struct CSParameters {
     pub jpeg: JpegParameters,
     pub png: PngParameters,
@@ -146,21 +463,82 @@ fn initialize_parameters() -> CSParameters {
         progressive: true,
         optimize: false,
         preserve_icc: true,
+        arithmetic_coding: false,
+        restart_interval: 0,
+        restart_in_rows: 0,
+        scan_script: JpegScanScript::new(),
+        optimize_scans: true,
     };
     let png = PngParameters {
         quality: 80,
         force_zopfli: false,
         optimization_level: 3,
         optimize: false,
+        min_quality: 0,
+        quality_fallback: PngQualityFallback::Lossless,
+        dithering_level: 1.0,
+        speed: 4,
+        max_colors: 256,
+        posterization: 0,
+        last_index_transparent: false,
+        optimize_lossy: true,
+        high_bit_depth: PngHighBitDepth::Lossless,
+        reductions: PngReductions {
+            bit_depth: true,
+            color_type: true,
+            palette: true,
+            grayscale: true,
+            alpha: false,
+        },
+        interlace: PngInterlace::Remove,
+        keep_text_keys: Vec::new(),
+        text_chunks: Vec::new(),
+        timeout: None,
+        zopfli_iterations: 0,
+        deflate_level: 0,
+    };
+    let gif = GifParameters {
+        quality: 80,
+        optimize: false,
+        lossy_quality: 0,
+        motion_quality: 0,
+        fast: false,
+        extra_effort: false,
+        preserve_delays: false,
+        max_frame_rate: 0,
+        max_frames: 0,
+        trim_start: None,
+        trim_end: None,
+        keep_extensions: false,
     };
-    let gif = GifParameters { quality: 80 };
     let webp = WebPParameters {
         quality: 80,
         lossless: false,
+        method: 4,
+        sns_strength: 50,
+        filter_strength: 60,
+        filter_sharpness: 0,
+        segments: 4,
+        preprocessing: 0,
+        alpha_quality: 100,
+        near_lossless: 100,
+        exact: false,
+        pass: 1,
+        image_hint: WebPImageHint::Default,
+        target_size: 0,
+        target_psnr: 0.0,
+        kmin: 0,
+        kmax: 0,
+        allow_mixed: false,
+        minimize_size: false,
     };
     let tiff = TiffParameters {
         algorithm: Deflate,
         deflate_level: TiffDeflateLevel::Balanced,
+        color_type: TiffColorType::Original,
+        predictor: TiffPredictor::None,
+        jpeg_quality: 80,
+        threshold: TiffThreshold::Global,
     };
 
     CSParameters {
@@ -1,29 +1,30 @@
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Cursor, Write};
 use std::num::NonZeroU8;
 
+use crate::animation::{Animation, AnimationFrame};
 use crate::error::CaesiumError;
+use crate::parameters::{PngHighBitDepth, PngInterlace, PngQualityFallback, PngTextChunk};
 use crate::resize::resize;
+use crate::utils::is_apng;
 use crate::CSParameters;
-use image::ImageFormat;
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, ImageFormat};
 use imagequant::RGBA;
-use oxipng::Deflaters::Zopfli;
+use oxipng::Deflaters::{Libdeflater, Zopfli};
+use oxipng::Interlacing;
 
 use bytes::Bytes;
-use img_parts::png::Png as PartsPng;
+use img_parts::png::{Png as PartsPng, PngChunk};
 use img_parts::{ImageEXIF, ImageICC};
 
 pub fn compress(input_path: String, output_path: String, parameters: &CSParameters) -> Result<(), CaesiumError> {
-    let mut in_file = fs::read(input_path).map_err(|e| CaesiumError {
+    let in_file = fs::read(input_path).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20200,
     })?;
 
-    if parameters.width > 0 || parameters.height > 0 {
-        in_file = resize(&in_file, parameters.width, parameters.height, ImageFormat::Png)?;
-    }
-
     let optimized_png = compress_in_memory(&in_file, parameters)?;
     let mut output_file_buffer = File::create(output_path).map_err(|e| CaesiumError {
         message: e.to_string(),
@@ -40,39 +41,56 @@ pub fn compress(input_path: String, output_path: String, parameters: &CSParamete
 }
 
 pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
-    if parameters.width > 0 || parameters.height > 0 {
+    let metadata = extract_metadata(in_file, parameters);
+    let mut parameters = parameters.clone();
+    if parameters.png.interlace == PngInterlace::Keep {
+        parameters.png.interlace = if ihdr_field(in_file, 12) == Some(1) {
+            PngInterlace::Force
+        } else {
+            PngInterlace::Remove
+        };
+    }
+    let parameters = &parameters;
+
+    let png_vec = if is_apng(in_file) {
+        compress_animated(in_file, parameters)?
+    } else if parameters.width > 0 || parameters.height > 0 {
         let input = resize(in_file, parameters.width, parameters.height, ImageFormat::Png)?;
 
         if parameters.png.optimize {
-            Ok(lossless(&input, parameters)?)
+            lossless(&input, parameters)?
         } else {
-            Ok(lossy(&input, parameters)?)
+            lossy(&input, parameters)?
         }
     } else if parameters.png.optimize {
-        Ok(lossless(in_file, parameters)?)
+        lossless(in_file, parameters)?
     } else {
-        Ok(lossy(in_file, parameters)?)
-    }
+        lossy(in_file, parameters)?
+    };
+
+    save_metadata(png_vec, metadata, parameters)
 }
 
 fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
-    let (iccp, exif) = if parameters.keep_metadata {
-        extract_metadata(in_file)
-    } else {
-        (None, None)
-    };
+    if ihdr_field(in_file, 8) == Some(16) {
+        match parameters.png.high_bit_depth {
+            PngHighBitDepth::Lossless => return lossless(in_file, parameters),
+            PngHighBitDepth::Error => {
+                return Err(CaesiumError {
+                    message: "Lossy compression of 16-bit PNG is not allowed".into(),
+                    code: 20217,
+                })
+            }
+            PngHighBitDepth::Quantize => {}
+        }
+    }
 
     let rgba_bitmap = lodepng::decode32(in_file).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20204,
     })?;
 
-    let mut liq = imagequant::new();
-    liq.set_quality(0, parameters.png.quality as u8)
-        .map_err(|e| CaesiumError {
-            message: e.to_string(),
-            code: 20205,
-        })?;
+    let liq = quantizer(parameters)?;
 
     let mut liq_image = liq
         .new_image(
@@ -86,28 +104,36 @@ fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumEr
             code: 20206,
         })?;
 
-    let mut quantization = liq.quantize(&mut liq_image).map_err(|e| CaesiumError {
-        message: e.to_string(),
-        code: 20207,
-    })?;
+    let mut quantization = match liq.quantize(&mut liq_image) {
+        Ok(q) => q,
+        Err(imagequant::Error::QualityTooLow) if parameters.png.quality_fallback == PngQualityFallback::Lossless => {
+            return lossless(in_file, parameters);
+        }
+        Err(e) => {
+            return Err(CaesiumError {
+                message: e.to_string(),
+                code: 20207,
+            })
+        }
+    };
+    quantization
+        .set_dithering_level(parameters.png.dithering_level)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20216,
+        })?;
 
     let (palette, pixels) = quantization.remapped(&mut liq_image).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20208,
     })?;
 
-    let palette = palette
-        .iter()
-        .map(|px| {
-            if px.a == 0 {
-                RGBA { r: 0, g: 0, b: 0, a: 0 }
-            } else {
-                *px
-            }
-        })
-        .collect::<Vec<RGBA>>();
+    let palette = clear_transparent_colors(&palette);
 
     let mut encoder = lodepng::Encoder::new();
+    if parameters.png.interlace == PngInterlace::Force {
+        encoder.info_png_mut().interlace_method = 1;
+    }
     encoder.set_palette(palette.as_slice()).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20212,
@@ -119,30 +145,309 @@ fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumEr
             code: 20209,
         })?;
 
-    if parameters.keep_metadata && (iccp.is_some() || exif.is_some()) {
-        return save_metadata(png_vec, iccp, exif);
+    let png_vec = if parameters.png.optimize_lossy {
+        lossless(&png_vec, parameters)?
+    } else {
+        png_vec
+    };
+
+    Ok(png_vec)
+}
+
+fn compress_animated(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    if !parameters.png.optimize {
+        return lossy_animated(in_file, parameters);
+    }
+
+    if parameters.width == 0 && parameters.height == 0 {
+        return lossless(in_file, parameters);
     }
 
+    let mut animation = decode_apng(in_file)?;
+    animation.resize(parameters.width, parameters.height);
+    lossless(&encode_apng(&animation)?, parameters)
+}
+
+fn lossy_animated(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let mut animation = decode_apng(in_file)?;
+    animation.resize(parameters.width, parameters.height);
+    let width = animation.width as usize;
+    let height = animation.height as usize;
+
+    let bitmaps = animation
+        .frames
+        .iter()
+        .map(|f| {
+            f.image
+                .pixels()
+                .map(|px| RGBA::new(px[0], px[1], px[2], px[3]))
+                .collect()
+        })
+        .collect::<Vec<Vec<RGBA>>>();
+
+    // All the frames share a single palette, learnt from every frame
+    let liq = quantizer(parameters)?;
+    let mut histogram = imagequant::Histogram::new(&liq);
+    for bitmap in bitmaps.iter() {
+        let mut liq_image = liq
+            .new_image_borrowed(bitmap, width, height, 0.0)
+            .map_err(|e| CaesiumError {
+                message: e.to_string(),
+                code: 20206,
+            })?;
+        histogram.add_image(&liq, &mut liq_image).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20218,
+        })?;
+    }
+
+    let palette = match histogram.quantize(&liq) {
+        Ok(mut q) => q.palette_vec(),
+        Err(imagequant::Error::QualityTooLow) if parameters.png.quality_fallback == PngQualityFallback::Lossless => {
+            let mut parameters = parameters.clone();
+            parameters.png.optimize = true;
+            return compress_animated(in_file, &parameters);
+        }
+        Err(e) => {
+            return Err(CaesiumError {
+                message: e.to_string(),
+                code: 20207,
+            })
+        }
+    };
+
+    // Remapping refines non fixed colors, fix them so every frame keeps the same palette
+    let mut quantization =
+        imagequant::QuantizationResult::from_palette(&liq, &palette, 0.0).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20207,
+        })?;
+    quantization
+        .set_dithering_level(parameters.png.dithering_level)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20216,
+        })?;
+
+    let mut frames = Vec::with_capacity(bitmaps.len());
+    for (bitmap, frame) in bitmaps.iter().zip(animation.frames.iter()) {
+        let mut liq_image = liq
+            .new_image_borrowed(bitmap, width, height, 0.0)
+            .map_err(|e| CaesiumError {
+                message: e.to_string(),
+                code: 20206,
+            })?;
+        let (_, pixels) = quantization.remapped(&mut liq_image).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20208,
+        })?;
+        frames.push((pixels, frame.delay_ms));
+    }
+
+    let palette = clear_transparent_colors(&palette);
+    let png_vec = write_apng(
+        &animation,
+        ::png::ColorType::Indexed,
+        Some(&palette),
+        frames.iter().map(|(pixels, delay_ms)| (pixels.as_slice(), *delay_ms)),
+    )?;
+
+    let png_vec = if parameters.png.optimize_lossy {
+        lossless(&png_vec, parameters)?
+    } else {
+        png_vec
+    };
+
     Ok(png_vec)
 }
 
+pub fn decode_apng(in_file: &[u8]) -> Result<Animation, CaesiumError> {
+    let decoder = PngDecoder::new(Cursor::new(in_file)).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20219,
+    })?;
+    let frames = decoder
+        .apng()
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20219,
+        })?
+        .into_frames()
+        .collect_frames()
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20220,
+        })?;
+
+    let Some(first_frame) = frames.first() else {
+        return Err(CaesiumError {
+            message: "APNG has no frames".into(),
+            code: 20221,
+        });
+    };
+    let (width, height) = first_frame.buffer().dimensions();
+
+    // acTL: num_frames (4 bytes), num_plays (4 bytes)
+    let loop_count = PartsPng::from_bytes(Bytes::copy_from_slice(in_file))
+        .ok()
+        .and_then(|png| {
+            png.chunk_by_type(*b"acTL").and_then(|c| {
+                c.contents()
+                    .get(4..8)
+                    .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            })
+        })
+        .unwrap_or(0);
+
+    Ok(Animation {
+        width,
+        height,
+        loop_count,
+        background: None,
+        frames: frames
+            .into_iter()
+            .map(|f| {
+                let (numer, denom) = f.delay().numer_denom_ms();
+                AnimationFrame {
+                    delay_ms: (numer as f64 / denom.max(1) as f64).round() as u32,
+                    image: f.into_buffer(),
+                }
+            })
+            .collect(),
+    })
+}
+
+/// Encodes full canvas RGBA frames, without any compression effort.
+pub fn encode_apng(animation: &Animation) -> Result<Vec<u8>, CaesiumError> {
+    write_apng(
+        animation,
+        ::png::ColorType::Rgba,
+        None,
+        animation
+            .frames
+            .iter()
+            .map(|f| (f.image.as_raw().as_slice(), f.delay_ms)),
+    )
+}
+
+fn write_apng<'a>(
+    animation: &Animation,
+    color_type: ::png::ColorType,
+    palette: Option<&[RGBA]>,
+    frames: impl ExactSizeIterator<Item = (&'a [u8], u32)>,
+) -> Result<Vec<u8>, CaesiumError> {
+    let map_error = |e: ::png::EncodingError| CaesiumError {
+        message: e.to_string(),
+        code: 20222,
+    };
+
+    let mut output = Vec::new();
+    let mut encoder = ::png::Encoder::new(&mut output, animation.width, animation.height);
+    encoder.set_color(color_type);
+    encoder.set_depth(::png::BitDepth::Eight);
+    if let Some(palette) = palette {
+        encoder.set_palette(palette.iter().flat_map(|px| [px.r, px.g, px.b]).collect::<Vec<u8>>());
+        encoder.set_trns(palette.iter().map(|px| px.a).collect::<Vec<u8>>());
+    }
+    encoder
+        .set_animated(frames.len() as u32, animation.loop_count)
+        .map_err(map_error)?;
+
+    let mut writer = encoder.write_header().map_err(map_error)?;
+    for (data, delay_ms) in frames {
+        // fcTL delays are 16 bits fractions, fall back to centiseconds for long frames
+        let (numerator, denominator) = if delay_ms <= u16::MAX as u32 {
+            (delay_ms as u16, 1000)
+        } else {
+            ((delay_ms / 10).min(u16::MAX as u32) as u16, 100)
+        };
+        writer.set_frame_delay(numerator, denominator).map_err(map_error)?;
+        writer.write_image_data(data).map_err(map_error)?;
+    }
+    writer.finish().map_err(map_error)?;
+
+    Ok(output)
+}
+
+fn quantizer(parameters: &CSParameters) -> Result<imagequant::Attributes, CaesiumError> {
+    let quality = parameters.png.quality as u8;
+    let mut liq = imagequant::new();
+    liq.set_quality((parameters.png.min_quality as u8).min(quality), quality)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20205,
+        })?;
+    liq.set_speed(parameters.png.speed as i32).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20213,
+    })?;
+    liq.set_max_colors(parameters.png.max_colors)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20214,
+        })?;
+    liq.set_min_posterization(parameters.png.posterization)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20215,
+        })?;
+    liq.set_last_index_transparent(parameters.png.last_index_transparent);
+
+    Ok(liq)
+}
+
+fn clear_transparent_colors(palette: &[RGBA]) -> Vec<RGBA> {
+    palette
+        .iter()
+        .map(|px| {
+            if px.a == 0 {
+                RGBA { r: 0, g: 0, b: 0, a: 0 }
+            } else {
+                *px
+            }
+        })
+        .collect()
+}
+
 fn lossless(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
-    let mut oxipng_options = oxipng::Options::default();
+    let optimization_level = parameters.png.optimization_level.clamp(0, 6);
+    let mut oxipng_options = oxipng::Options::from_preset(optimization_level);
     if !parameters.keep_metadata {
         oxipng_options.strip = oxipng::StripChunks::Safe;
     }
+    oxipng_options.bit_depth_reduction = parameters.png.reductions.bit_depth;
+    oxipng_options.color_type_reduction = parameters.png.reductions.color_type;
+    oxipng_options.palette_reduction = parameters.png.reductions.palette;
+    oxipng_options.grayscale_reduction = parameters.png.reductions.grayscale;
+    oxipng_options.optimize_alpha = parameters.png.reductions.alpha;
+    oxipng_options.interlace = match parameters.png.interlace {
+        PngInterlace::Keep => None,
+        PngInterlace::Force => Some(Interlacing::Adam7),
+        PngInterlace::Remove => Some(Interlacing::None),
+    };
+    // oxipng returns the input as is when its result is larger, which would keep the input interlacing
+    let interlaced = ihdr_field(in_file, 12) == Some(1);
+    oxipng_options.force = match parameters.png.interlace {
+        PngInterlace::Keep => false,
+        PngInterlace::Force => !interlaced,
+        PngInterlace::Remove => interlaced,
+    };
 
-    if parameters.png.optimize && parameters.png.force_zopfli {
-        let mut iterations = 15;
-        if in_file.len() > 2000000 {
-            iterations = 5;
-        }
+    oxipng_options.timeout = parameters.png.timeout;
+
+    if parameters.png.force_zopfli {
+        let iterations = match parameters.png.zopfli_iterations {
+            0 if in_file.len() > 2000000 => 5,
+            0 => 15,
+            i => i,
+        };
         oxipng_options.deflate = Zopfli {
             iterations: NonZeroU8::new(iterations).unwrap(),
         };
-    } else {
-        let optimization_level = parameters.png.optimization_level.clamp(0, 6);
-        oxipng_options = oxipng::Options::from_preset(optimization_level);
+    } else if parameters.png.deflate_level > 0 {
+        oxipng_options.deflate = Libdeflater {
+            compression: parameters.png.deflate_level,
+        };
     }
 
     let optimized_png = oxipng::optimize_from_memory(in_file, &oxipng_options).map_err(|e| CaesiumError {
@@ -153,22 +458,100 @@ fn lossless(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, Caesiu
     Ok(optimized_png)
 }
 
-fn extract_metadata(image: &[u8]) -> (Option<Bytes>, Option<Bytes>) {
+/// Reads a byte of the IHDR chunk data, such as the bit depth (8) or the interlace method (12).
+fn ihdr_field(in_file: &[u8], index: usize) -> Option<u8> {
+    // The IHDR chunk must come first, right after the 8 bytes signature
+    if in_file.len() < 29 || &in_file[12..16] != b"IHDR" {
+        return None;
+    }
+    in_file.get(16 + index).copied()
+}
+
+/// Metadata carried over from the input to the compressed image.
+struct Metadata {
+    iccp: Option<Bytes>,
+    exif: Option<Bytes>,
+    text: Vec<PngChunk>,
+}
+
+const TEXT_CHUNKS: [[u8; 4]; 3] = [*b"tEXt", *b"zTXt", *b"iTXt"];
+
+fn extract_metadata(image: &[u8], parameters: &CSParameters) -> Metadata {
+    let mut metadata = Metadata {
+        iccp: None,
+        exif: None,
+        text: Vec::new(),
+    };
+    if !parameters.keep_metadata && parameters.png.keep_text_keys.is_empty() {
+        return metadata;
+    }
     let Ok(png) = PartsPng::from_bytes(Bytes::from(image.to_vec())) else {
-        return (None, None);
+        return metadata;
     };
-    let iccp = png.icc_profile().map(|b| Bytes::copy_from_slice(&b));
-    let exif = png.exif().map(|b| Bytes::copy_from_slice(&b));
-    (iccp, exif)
+
+    if parameters.keep_metadata {
+        metadata.iccp = png.icc_profile().map(|b| Bytes::copy_from_slice(&b));
+        metadata.exif = png.exif().map(|b| Bytes::copy_from_slice(&b));
+    }
+    let is_kept = |keyword: &[u8]| {
+        let is_replaced = parameters
+            .png
+            .text_chunks
+            .iter()
+            .any(|c| to_latin1(&c.keyword).as_deref() == Some(keyword));
+        let is_selected = parameters.keep_metadata
+            || parameters
+                .png
+                .keep_text_keys
+                .iter()
+                .any(|k| to_latin1(k).as_deref() == Some(keyword));
+        is_selected && !is_replaced
+    };
+    metadata.text = png
+        .chunks()
+        .iter()
+        .filter(|c| TEXT_CHUNKS.contains(&c.kind()))
+        .filter(|c| is_kept(c.contents().split(|b| *b == 0).next().unwrap_or_default()))
+        .cloned()
+        .collect();
+
+    metadata
 }
 
-fn save_metadata(image_buffer: Vec<u8>, iccp: Option<Bytes>, exif: Option<Bytes>) -> Result<Vec<u8>, CaesiumError> {
+fn save_metadata(
+    image_buffer: Vec<u8>,
+    metadata: Metadata,
+    parameters: &CSParameters,
+) -> Result<Vec<u8>, CaesiumError> {
+    if metadata.iccp.is_none()
+        && metadata.exif.is_none()
+        && metadata.text.is_empty()
+        && parameters.png.text_chunks.is_empty()
+    {
+        return Ok(image_buffer);
+    }
+
     let mut png = PartsPng::from_bytes(Bytes::from(image_buffer)).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20210,
     })?;
-    png.set_icc_profile(iccp);
-    png.set_exif(exif);
+    if parameters.keep_metadata {
+        png.set_icc_profile(metadata.iccp);
+        png.set_exif(metadata.exif);
+    }
+
+    let text = metadata
+        .text
+        .into_iter()
+        .chain(parameters.png.text_chunks.iter().map(encode_text_chunk));
+    let chunks = png.chunks_mut();
+    chunks.retain(|c| !TEXT_CHUNKS.contains(&c.kind()));
+    let position = chunks
+        .iter()
+        .position(|c| c.kind() == *b"IDAT")
+        .unwrap_or(chunks.len().saturating_sub(1));
+    chunks.splice(position..position, text);
+
     let mut output = Vec::new();
     png.encoder().write_to(&mut output).map_err(|e| CaesiumError {
         message: e.to_string(),
@@ -176,3 +559,58 @@ fn save_metadata(image_buffer: Vec<u8>, iccp: Option<Bytes>, exif: Option<Bytes>
     })?;
     Ok(output)
 }
+
+/// Encodes a text chunk as tEXt when it fits in Latin-1, as uncompressed iTXt otherwise.
+fn encode_text_chunk(chunk: &PngTextChunk) -> PngChunk {
+    let mut contents = to_latin1(&chunk.keyword).unwrap_or_default();
+    contents.push(0);
+    match to_latin1(&chunk.text) {
+        Some(text) => {
+            contents.extend(text);
+            PngChunk::new(*b"tEXt", Bytes::from(contents))
+        }
+        None => {
+            // No compression, empty language tag and translated keyword
+            contents.extend([0, 0, 0, 0]);
+            contents.extend(chunk.text.as_bytes());
+            PngChunk::new(*b"iTXt", Bytes::from(contents))
+        }
+    }
+}
+
+fn to_latin1(text: &str) -> Option<Vec<u8>> {
+    text.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+/// Reads the tEXt, zTXt and iTXt chunks of a PNG, wherever they are placed.
+pub fn read_text_chunks(in_file: &[u8]) -> Result<Vec<PngTextChunk>, CaesiumError> {
+    let mut reader = ::png::Decoder::new(Cursor::new(in_file))
+        .read_info()
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20223,
+        })?;
+    reader.finish().map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20223,
+    })?;
+
+    let info = reader.info();
+    let mut text: Vec<PngTextChunk> = info
+        .uncompressed_latin1_text
+        .iter()
+        .map(|c| PngTextChunk::new(c.keyword.clone(), c.text.clone()))
+        .collect();
+    for chunk in info.compressed_latin1_text.iter() {
+        if let Ok(t) = chunk.get_text() {
+            text.push(PngTextChunk::new(chunk.keyword.clone(), t));
+        }
+    }
+    for chunk in info.utf8_text.iter() {
+        if let Ok(t) = chunk.get_text() {
+            text.push(PngTextChunk::new(chunk.keyword.clone(), t));
+        }
+    }
+
+    Ok(text)
+}
@@ -0,0 +1,92 @@
+use std::io::Cursor;
+
+use image::ImageReader;
+
+use crate::error::CaesiumError;
+use crate::parameters::PngTextChunk;
+use crate::utils::get_filetype_from_memory;
+use crate::SupportedFileTypes;
+
+/// Struct representing the information read from an image without compressing it.
+///
+/// Fields:
+/// - `format`: Format of the image
+/// - `width`: Width of the image
+/// - `height`: Height of the image
+/// - `png_text`: tEXt, zTXt and iTXt chunks of a PNG image
+/// - `gif_comments`: Comment extensions of a GIF image
+/// - `gif_application_extensions`: Application extensions of a GIF image, including the loop extension
+/// - `gif_loop_count`: Loop count stored in a GIF image, 0 meaning forever, `None` when the animation is played once
+pub struct ImageInfo {
+    pub format: SupportedFileTypes,
+    pub width: u32,
+    pub height: u32,
+    pub png_text: Vec<PngTextChunk>,
+    pub gif_comments: Vec<String>,
+    pub gif_application_extensions: Vec<GifApplicationExtension>,
+    pub gif_loop_count: Option<u16>,
+}
+
+/// Struct representing an application extension of a GIF image.
+///
+/// Fields:
+/// - `identifier`: Application identifier, e.g. `NETSCAPE` or `XMP Data`
+/// - `authentication_code`: Application authentication code, e.g. `2.0` or `XMP`
+/// - `data`: Data sub-blocks following the identifier with their length bytes, without the block terminator.
+///   Payloads such as XMP are stored raw and rely on this layout
+#[derive(Clone, PartialEq, Debug)]
+pub struct GifApplicationExtension {
+    pub identifier: String,
+    pub authentication_code: [u8; 3],
+    pub data: Vec<u8>,
+}
+
+pub fn probe_in_memory(in_file: &[u8]) -> Result<ImageInfo, CaesiumError> {
+    let format = get_filetype_from_memory(in_file);
+    if format == SupportedFileTypes::Unkn {
+        return Err(CaesiumError {
+            message: "Unknown file type".into(),
+            code: 10500,
+        });
+    }
+
+    let reader = ImageReader::new(Cursor::new(in_file))
+        .with_guessed_format()
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 10501,
+        })?;
+    let (width, height) = reader.into_dimensions().map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10501,
+    })?;
+
+    let png_text = match format {
+        #[cfg(feature = "png")]
+        SupportedFileTypes::Png => crate::png::read_text_chunks(in_file)?,
+        _ => Vec::new(),
+    };
+
+    let (gif_comments, gif_application_extensions, gif_loop_count) = match format {
+        #[cfg(feature = "gif")]
+        SupportedFileTypes::Gif => {
+            let extensions = crate::gif::read_extensions(in_file)?;
+            (
+                extensions.comments,
+                extensions.application_extensions,
+                extensions.loop_count,
+            )
+        }
+        _ => (Vec::new(), Vec::new(), None),
+    };
+
+    Ok(ImageInfo {
+        format,
+        width,
+        height,
+        png_text,
+        gif_comments,
+        gif_application_extensions,
+        gif_loop_count,
+    })
+}
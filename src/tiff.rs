@@ -1,16 +1,48 @@
+use std::borrow::Cow;
 use std::fs::File;
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, Read, Seek, Write};
 use std::panic;
 
-use image::ImageFormat::Tiff;
-use tiff::encoder::colortype::{RGB8, RGBA8};
-use tiff::encoder::compression::{Deflate, DeflateLevel, Lzw, Packbits, Uncompressed};
-use tiff::encoder::TiffEncoder;
+use fax::encoder::Encoder;
+use fax::maps::{black, white, EOL};
+use fax::{BitWriter, Bits, Color, VecWriter};
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgb, RgbImage, RgbaImage};
+use tiff::decoder::ifd::Value;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::compression::{CompressionAlgorithm, Deflate, DeflateLevel, Lzw, Packbits};
+use tiff::encoder::{DirectoryEncoder, Predictor, Rational, SRational, TiffEncoder, TiffKindStandard, TiffValue};
+use tiff::tags::{CompressionMethod, IfdPointer, PhotometricInterpretation, ResolutionUnit, Tag, Type};
+use tiff::{ColorType, TiffError, TiffResult};
 
 use crate::error::CaesiumError;
-use crate::parameters::TiffCompression;
+#[cfg(feature = "jpg")]
+use crate::jpeg;
+use crate::parameters::{TiffColorType, TiffCompression, TiffPredictor, TiffThreshold};
 use crate::resize::resize_image;
-use crate::{CSParameters, TiffDeflateLevel};
+use crate::utils::get_filetype_from_memory;
+use crate::{CSParameters, SupportedFileTypes, TiffDeflateLevel};
+
+/// Uncompressed size of a strip, as in the `tiff` crate encoder.
+const STRIP_SIZE: usize = 1_000_000;
+
+/// Tags the `tiff` crate has no name for.
+const YCBCR_SUBSAMPLING: Tag = Tag::Unknown(530);
+const REFERENCE_BLACK_WHITE: Tag = Tag::Unknown(532);
+const T4_OPTIONS: Tag = Tag::Unknown(292);
+const DOCUMENT_NAME: Tag = Tag::Unknown(269);
+const INTEROPERABILITY_IFD: Tag = Tag::Unknown(0xA005);
+
+/// Tags of a page kept with `keep_metadata`, besides the EXIF IFD.
+const METADATA_TAGS: [Tag; 8] = [
+    Tag::XResolution,
+    Tag::YResolution,
+    Tag::ResolutionUnit,
+    Tag::IccProfile,
+    Tag::Artist,
+    Tag::Copyright,
+    Tag::DateTime,
+    DOCUMENT_NAME,
+];
 
 pub fn compress(input_path: String, output_path: String, parameters: &CSParameters) -> Result<(), CaesiumError> {
     let mut input_file = File::open(input_path).map_err(|e| CaesiumError {
@@ -38,79 +70,775 @@ pub fn compress(input_path: String, output_path: String, parameters: &CSParamete
     Ok(())
 }
 
-pub fn compress_in_memory(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
-    let decoding_result = match panic::catch_unwind(|| image::load_from_memory_with_format(in_file.as_slice(), Tiff)) {
-        Ok(i) => i,
-        Err(_) => {
-            return Err(CaesiumError {
-                message: "Failed to decode TIFF image".to_string(),
-                code: 20504,
-            });
+pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let pages = decode_pages(in_file, parameters.keep_metadata)?;
+    encode_pages(prepare_pages(pages, parameters), parameters)
+}
+
+/// Splits a multipage TIFF into single page TIFF files, compressed with the given parameters.
+pub fn split_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<Vec<u8>>, CaesiumError> {
+    prepare_pages(decode_pages(in_file, parameters.keep_metadata)?, parameters)
+        .into_iter()
+        .map(|page| encode_pages(vec![page], parameters))
+        .collect()
+}
+
+/// Assembles several images into one multipage TIFF. Every page of a TIFF input is kept, any other
+/// supported format adds a single page.
+pub fn assemble_in_memory(images: &[Vec<u8>], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let mut pages = vec![];
+    for image in images {
+        if get_filetype_from_memory(image) == SupportedFileTypes::Tiff {
+            pages.extend(decode_pages(image, parameters.keep_metadata)?);
+        } else {
+            let image = image::load_from_memory(image).map_err(|e| CaesiumError {
+                message: e.to_string(),
+                code: 20508,
+            })?;
+            pages.push(Page::new(image, PageColor::Native));
         }
+    }
+
+    if pages.is_empty() {
+        return Err(CaesiumError {
+            message: "No images to assemble".to_string(),
+            code: 20509,
+        });
+    }
+
+    encode_pages(prepare_pages(pages, parameters), parameters)
+}
+
+/// A decoded TIFF page. CMYK pages keep their inks in an RGBA8 buffer and bilevel pages are Luma8 with only
+/// black and white pixels, so that both can be resized like any other image.
+struct Page {
+    image: DynamicImage,
+    color: PageColor,
+    metadata: Metadata,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum PageColor {
+    Native,
+    Cmyk,
+    Bilevel,
+}
+
+impl Page {
+    fn new(image: DynamicImage, color: PageColor) -> Page {
+        Page {
+            image,
+            color,
+            metadata: Metadata::default(),
+        }
+    }
+
+    fn is_gray(&self) -> bool {
+        self.color == PageColor::Bilevel || !self.image.color().has_color()
+    }
+
+    fn resize(self, width: u32, height: u32) -> Page {
+        let image = resize_image(self.image, width, height);
+        let image = match self.color {
+            PageColor::Bilevel => DynamicImage::ImageLuma8(threshold(image.to_luma8())),
+            _ => image,
+        };
+        Page { image, ..self }
+    }
+
+    fn convert(self, color_type: TiffColorType) -> Page {
+        if color_type == TiffColorType::Original {
+            return self;
+        }
+        // An ICC profile only stays valid between gray pages or between RGB pages
+        let keep_icc = self.color != PageColor::Cmyk && self.is_gray() == (color_type == TiffColorType::Gray8);
+        let image = match self.color {
+            PageColor::Cmyk => DynamicImage::ImageRgb8(cmyk_to_rgb(&self.image.to_rgba8())),
+            _ => self.image,
+        };
+        let image = match color_type {
+            TiffColorType::Rgb8 => DynamicImage::ImageRgb8(image.to_rgb8()),
+            TiffColorType::Rgba8 => DynamicImage::ImageRgba8(image.to_rgba8()),
+            TiffColorType::Gray8 => DynamicImage::ImageLuma8(image.to_luma8()),
+            TiffColorType::Original => image,
+        };
+        let mut page = Page {
+            image,
+            color: PageColor::Native,
+            ..self
+        };
+        if !keep_icc {
+            page.metadata.remove(Tag::IccProfile);
+        }
+        page
+    }
+
+    fn binarize(self, threshold: TiffThreshold) -> Page {
+        let gray = match self.color {
+            PageColor::Bilevel => return self,
+            PageColor::Cmyk => DynamicImage::ImageRgb8(cmyk_to_rgb(&self.image.to_rgba8())).to_luma8(),
+            PageColor::Native => self.image.to_luma8(),
+        };
+        let image = match threshold {
+            TiffThreshold::Global => global_threshold(gray),
+            TiffThreshold::Adaptive => adaptive_threshold(gray),
+        };
+        let mut page = Page {
+            image: DynamicImage::ImageLuma8(image),
+            color: PageColor::Bilevel,
+            ..self
+        };
+        page.metadata.remove(Tag::IccProfile);
+        page
+    }
+}
+
+/// Tags of a page kept when `keep_metadata` is set, and the entries of its EXIF IFD.
+#[derive(Default)]
+struct Metadata {
+    tags: Vec<(Tag, TagValue)>,
+    exif: Vec<(Tag, TagValue)>,
+}
+
+impl Metadata {
+    fn contains(&self, tag: Tag) -> bool {
+        self.tags.iter().any(|(t, _)| *t == tag)
+    }
+
+    fn remove(&mut self, tag: Tag) {
+        self.tags.retain(|(t, _)| *t != tag);
+    }
+}
+
+/// A tag value read from the source file, keeping its field type.
+enum TagValue {
+    Byte(Vec<u8>),
+    Undefined(Vec<u8>),
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    SignedLong(Vec<i32>),
+    Rational(Vec<Rational>),
+    SignedRational(Vec<SRational>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+}
+
+impl TagValue {
+    /// Returns `None` for values that cannot be written back as they are.
+    fn new(field_type: Type, value: Value) -> Option<TagValue> {
+        let values = |value| match value {
+            Value::List(values) => values,
+            value => vec![value],
+        };
+        // Bytes stored inline in the entry come out as LONG values
+        let bytes = |value: Value| -> Option<Vec<u8>> {
+            let values = value.into_u32_vec().ok()?;
+            values.into_iter().map(|v| u8::try_from(v).ok()).collect()
+        };
+        let value = match field_type {
+            Type::BYTE => TagValue::Byte(bytes(value)?),
+            Type::UNDEFINED => TagValue::Undefined(bytes(value)?),
+            Type::ASCII => TagValue::Ascii(value.into_string().ok().filter(|s| s.is_ascii() && !s.contains('\0'))?),
+            Type::SHORT => TagValue::Short(value.into_u16_vec().ok()?),
+            Type::LONG => TagValue::Long(value.into_u32_vec().ok()?),
+            Type::SLONG => TagValue::SignedLong(value.into_i32_vec().ok()?),
+            Type::RATIONAL => TagValue::Rational(
+                values(value)
+                    .into_iter()
+                    .map(|v| match v {
+                        Value::Rational(n, d) => Some(Rational { n, d }),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?,
+            ),
+            Type::SRATIONAL => TagValue::SignedRational(
+                values(value)
+                    .into_iter()
+                    .map(|v| match v {
+                        Value::SRational(n, d) => Some(SRational { n, d }),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?,
+            ),
+            Type::FLOAT => TagValue::Float(value.into_f32_vec().ok()?),
+            Type::DOUBLE => TagValue::Double(value.into_f64_vec().ok()?),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn write<W: Write + Seek>(
+        &self,
+        directory: &mut DirectoryEncoder<W, TiffKindStandard>,
+        tag: Tag,
+    ) -> TiffResult<()> {
+        match self {
+            TagValue::Byte(v) => directory.write_tag(tag, v.as_slice()),
+            TagValue::Undefined(v) => directory.write_tag(tag, Undefined(v)),
+            TagValue::Ascii(v) => directory.write_tag(tag, v.as_str()),
+            TagValue::Short(v) => directory.write_tag(tag, v.as_slice()),
+            TagValue::Long(v) => directory.write_tag(tag, v.as_slice()),
+            TagValue::SignedLong(v) => directory.write_tag(tag, v.as_slice()),
+            TagValue::Rational(v) => directory.write_tag(tag, v.as_slice()),
+            TagValue::SignedRational(v) => directory.write_tag(tag, v.as_slice()),
+            TagValue::Float(v) => directory.write_tag(tag, v.as_slice()),
+            TagValue::Double(v) => directory.write_tag(tag, v.as_slice()),
+        }
+    }
+}
+
+/// Bytes with the UNDEFINED field type, which the `tiff` crate would otherwise write as BYTE.
+struct Undefined<'a>(&'a [u8]);
+
+impl TiffValue for Undefined<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: Type = Type::UNDEFINED;
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0)
+    }
+}
+
+fn decode_pages(in_file: &[u8], keep_metadata: bool) -> Result<Vec<Page>, CaesiumError> {
+    match panic::catch_unwind(|| read_pages(in_file, keep_metadata)) {
+        Ok(pages) => pages,
+        Err(_) => Err(CaesiumError {
+            message: "Failed to decode TIFF image".to_string(),
+            code: 20504,
+        }),
+    }
+}
+
+fn read_pages(in_file: &[u8], keep_metadata: bool) -> Result<Vec<Page>, CaesiumError> {
+    let to_error = |e: TiffError| CaesiumError {
+        message: e.to_string(),
+        code: 20504,
     };
-    let mut image = match decoding_result {
-        Ok(i) => i,
-        Err(e) => {
-            return Err(CaesiumError {
-                message: e.to_string(),
-                code: 20504,
-            })
+    let mut decoder = Decoder::new(Cursor::new(in_file)).map_err(to_error)?;
+    let mut pages = vec![];
+    loop {
+        let (width, height) = decoder.dimensions().map_err(to_error)?;
+        let color_type = decoder.colortype().map_err(to_error)?;
+        let data = decoder.read_image().map_err(to_error)?;
+        let mut page = to_page(width, height, color_type, data).ok_or(CaesiumError {
+            message: format!("Unsupported TIFF color type ({color_type:?})"),
+            code: 20504,
+        })?;
+        if keep_metadata {
+            // Metadata that cannot be read is dropped rather than failing the page
+            page.metadata = read_metadata(&mut decoder).unwrap_or_default();
+        }
+        pages.push(page);
+
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image().map_err(to_error)?;
+    }
+    Ok(pages)
+}
+
+fn read_metadata(decoder: &mut Decoder<Cursor<&[u8]>>) -> TiffResult<Metadata> {
+    let Some(pointer) = decoder.ifd_pointer() else {
+        return Ok(Metadata::default());
+    };
+    let tags = read_tags(decoder, pointer, |tag| METADATA_TAGS.contains(&tag))?;
+    let exif = match decoder.find_tag(Tag::ExifDirectory)? {
+        // Offsets to other IFDs would point nowhere once copied
+        Some(pointer) => read_tags(decoder, pointer.into_ifd_pointer()?, |tag| tag != INTEROPERABILITY_IFD)?,
+        None => vec![],
+    };
+    Ok(Metadata { tags, exif })
+}
+
+fn read_tags(
+    decoder: &mut Decoder<Cursor<&[u8]>>,
+    pointer: IfdPointer,
+    keep: impl Fn(Tag) -> bool,
+) -> TiffResult<Vec<(Tag, TagValue)>> {
+    let directory = decoder.read_directory(pointer)?;
+    let mut reader = decoder.read_directory_tags(&directory);
+    let mut tags = vec![];
+    for (tag, entry) in directory.iter().filter(|(tag, _)| keep(*tag)) {
+        if let Some(value) = reader.find_tag(tag)?.and_then(|v| TagValue::new(entry.field_type(), v)) {
+            tags.push((tag, value));
+        }
+    }
+    Ok(tags)
+}
+
+fn to_page(width: u32, height: u32, color_type: ColorType, data: DecodingResult) -> Option<Page> {
+    let image = match (color_type, data) {
+        (ColorType::Gray(1), DecodingResult::U8(d)) => {
+            let row_bytes = (width as usize).div_ceil(8);
+            let pixels = d
+                .chunks_exact(row_bytes)
+                .take(height as usize)
+                .flat_map(|row| (0..width as usize).map(move |x| (row[x / 8] >> (7 - x % 8) & 1) * 255))
+                .collect();
+            let image = ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)?;
+            return Some(Page::new(image, PageColor::Bilevel));
+        }
+        (ColorType::CMYK(8), DecodingResult::U8(d)) => {
+            let image = ImageBuffer::from_raw(width, height, d).map(DynamicImage::ImageRgba8)?;
+            return Some(Page::new(image, PageColor::Cmyk));
+        }
+        (ColorType::Gray(8), DecodingResult::U8(d)) => {
+            DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, d)?)
         }
+        (
+            ColorType::Multiband {
+                bit_depth: 8,
+                num_samples: 2,
+            },
+            DecodingResult::U8(d),
+        ) => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, d)?),
+        (ColorType::RGB(8), DecodingResult::U8(d)) => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, d)?),
+        // JPEG compressed pages come out as full resolution YCbCr
+        (ColorType::YCbCr(8), DecodingResult::U8(d)) if d.len() == width as usize * height as usize * 3 => {
+            DynamicImage::ImageRgb8(ycbcr_to_rgb(ImageBuffer::from_raw(width, height, d)?))
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(d)) => {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, d)?)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(d)) => {
+            DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, d)?)
+        }
+        (
+            ColorType::Multiband {
+                bit_depth: 16,
+                num_samples: 2,
+            },
+            DecodingResult::U16(d),
+        ) => DynamicImage::ImageLumaA16(ImageBuffer::from_raw(width, height, d)?),
+        (ColorType::RGB(16), DecodingResult::U16(d)) => {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, d)?)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(d)) => {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, d)?)
+        }
+        _ => return None,
     };
+    Some(Page::new(image, PageColor::Native))
+}
+
+fn cmyk_to_rgb(cmyk: &RgbaImage) -> RgbImage {
+    RgbImage::from_fn(cmyk.width(), cmyk.height(), |x, y| {
+        let [c, m, y, k] = cmyk.get_pixel(x, y).0;
+        let white = 255 - k as u16;
+        Rgb([c, m, y].map(|ink| ((255 - ink as u16) * white / 255) as u8))
+    })
+}
+
+fn ycbcr_to_rgb(mut image: RgbImage) -> RgbImage {
+    image.pixels_mut().for_each(|p| {
+        let [y, cb, cr] = p.0.map(|v| v as f32);
+        let (cb, cr) = (cb - 128.0, cr - 128.0);
+        p.0 = [y + 1.402 * cr, y - 0.344136 * cb - 0.714136 * cr, y + 1.772 * cb]
+            .map(|v| v.round().clamp(0.0, 255.0) as u8);
+    });
+    image
+}
+
+fn threshold(mut image: GrayImage) -> GrayImage {
+    image
+        .pixels_mut()
+        .for_each(|p| p.0[0] = if p.0[0] < 128 { 0 } else { 255 });
+    image
+}
 
-    if parameters.width > 0 || parameters.height > 0 {
-        image = resize_image(image, parameters.width, parameters.height);
+/// Thresholds at the level that best separates the dark and light pixels of the page (Otsu's method).
+fn global_threshold(mut image: GrayImage) -> GrayImage {
+    let mut histogram = [0u64; 256];
+    image.pixels().for_each(|p| histogram[p.0[0] as usize] += 1);
+    let total = image.pixels().len() as f64;
+    let sum: f64 = histogram.iter().enumerate().map(|(i, &n)| i as f64 * n as f64).sum();
+
+    let (mut dark, mut dark_sum, mut best, mut level) = (0.0, 0.0, 0.0, 0);
+    for (i, &n) in histogram.iter().enumerate() {
+        dark += n as f64;
+        dark_sum += i as f64 * n as f64;
+        let light = total - dark;
+        if dark == 0.0 {
+            continue;
+        }
+        if light == 0.0 {
+            break;
+        }
+        let variance = dark * light * (dark_sum / dark - (sum - dark_sum) / light).powi(2);
+        if variance > best {
+            best = variance;
+            level = i;
+        }
     }
 
-    let color_type = image.color();
-    let output_buff = vec![];
-    let mut output_stream = Cursor::new(output_buff);
+    image
+        .pixels_mut()
+        .for_each(|p| p.0[0] = if p.0[0] as usize <= level { 0 } else { 255 });
+    image
+}
+
+/// Makes black every pixel noticeably darker than the mean of the surrounding area, an eighth of the page wide
+/// (Bradley's method), so that shadows and uneven lighting do not swallow the text.
+fn adaptive_threshold(image: GrayImage) -> GrayImage {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut integral = vec![0u64; (width + 1) * (height + 1)];
+    for y in 0..height {
+        let mut row_sum = 0;
+        for x in 0..width {
+            row_sum += image.get_pixel(x as u32, y as u32).0[0] as u64;
+            integral[(y + 1) * (width + 1) + x + 1] = integral[y * (width + 1) + x + 1] + row_sum;
+        }
+    }
+
+    let half = (width.max(height) / 16).max(1);
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let (x, y) = (x as usize, y as usize);
+        let (x0, y0) = (x.saturating_sub(half), y.saturating_sub(half));
+        let (x1, y1) = ((x + half + 1).min(width), (y + half + 1).min(height));
+        let area = ((x1 - x0) * (y1 - y0)) as u64;
+        let sum = integral[y1 * (width + 1) + x1] + integral[y0 * (width + 1) + x0]
+            - integral[y0 * (width + 1) + x1]
+            - integral[y1 * (width + 1) + x0];
+        let value = image.get_pixel(x as u32, y as u32).0[0] as u64;
+        // 15% darker than the mean
+        image::Luma([if value * area * 100 < sum * 85 { 0 } else { 255 }])
+    })
+}
+
+fn prepare_pages(pages: Vec<Page>, parameters: &CSParameters) -> Vec<Page> {
+    let ccitt = matches!(
+        parameters.tiff.algorithm,
+        TiffCompression::CcittGroup3 | TiffCompression::CcittGroup4
+    );
+    pages
+        .into_iter()
+        .map(|page| {
+            let page = page.convert(parameters.tiff.color_type);
+            let page = if parameters.width > 0 || parameters.height > 0 {
+                page.resize(parameters.width, parameters.height)
+            } else {
+                page
+            };
+            if ccitt {
+                page.binarize(parameters.tiff.threshold)
+            } else {
+                page
+            }
+        })
+        .collect()
+}
+
+fn encode_pages(pages: Vec<Page>, parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let mut output_stream = Cursor::new(vec![]);
     let mut encoder = TiffEncoder::new(&mut output_stream).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20505,
     })?;
 
-    macro_rules! write_with_compression {
-        ($compression:expr) => {
-            match color_type {
-                image::ColorType::Rgb8 => encoder.write_image_with_compression::<RGB8, _>(
-                    image.width(),
-                    image.height(),
-                    $compression,
-                    image.as_bytes(),
-                ),
-                image::ColorType::Rgba8 => encoder.write_image_with_compression::<RGBA8, _>(
-                    image.width(),
-                    image.height(),
-                    $compression,
-                    image.as_bytes(),
-                ),
-                _ => {
-                    return Err(CaesiumError {
-                        message: format!("Unsupported TIFF color type ({color_type:?})"),
-                        code: 20506,
-                    });
-                }
+    for page in pages {
+        write_page(&mut encoder, &page, parameters)?;
+    }
+
+    Ok(output_stream.into_inner())
+}
+
+/// Writes a page as its own directory, one compressed strip at a time.
+fn write_page<W: Write + Seek>(
+    encoder: &mut TiffEncoder<W>,
+    page: &Page,
+    parameters: &CSParameters,
+) -> Result<(), CaesiumError> {
+    let (width, height) = (page.image.width(), page.image.height());
+    let jpeg = parameters.tiff.algorithm == TiffCompression::Jpeg;
+    let ccitt = matches!(
+        parameters.tiff.algorithm,
+        TiffCompression::CcittGroup3 | TiffCompression::CcittGroup4
+    );
+    if jpeg
+        && (page.color == PageColor::Cmyk
+            || !matches!(page.image.color(), image::ColorType::L8 | image::ColorType::Rgb8))
+    {
+        return Err(CaesiumError {
+            message: format!(
+                "JPEG compression needs 8-bit grayscale or RGB pages ({:?})",
+                page.image.color()
+            ),
+            code: 20510,
+        });
+    }
+    if ccitt && width > u16::MAX as u32 {
+        return Err(CaesiumError {
+            message: format!("CCITT compression supports pages up to {} pixels wide", u16::MAX),
+            code: 20511,
+        });
+    }
+
+    let (photometric, bits_per_sample, extra_samples): (_, &[u16], _) = match (page.color, page.image.color()) {
+        (PageColor::Bilevel, _) if !jpeg => (PhotometricInterpretation::WhiteIsZero, &[1], None),
+        (PageColor::Cmyk, _) => (PhotometricInterpretation::CMYK, &[8, 8, 8, 8], None),
+        (_, image::ColorType::L8) => (PhotometricInterpretation::BlackIsZero, &[8], None),
+        (_, image::ColorType::La8) => (PhotometricInterpretation::BlackIsZero, &[8, 8], Some(2u16)),
+        (_, image::ColorType::Rgb8) if jpeg => (PhotometricInterpretation::YCbCr, &[8, 8, 8], None),
+        (_, image::ColorType::Rgb8) => (PhotometricInterpretation::RGB, &[8, 8, 8], None),
+        (_, image::ColorType::Rgba8) => (PhotometricInterpretation::RGB, &[8, 8, 8, 8], Some(2)),
+        (_, image::ColorType::L16) => (PhotometricInterpretation::BlackIsZero, &[16], None),
+        (_, image::ColorType::La16) => (PhotometricInterpretation::BlackIsZero, &[16, 16], Some(2)),
+        (_, image::ColorType::Rgb16) => (PhotometricInterpretation::RGB, &[16, 16, 16], None),
+        (_, image::ColorType::Rgba16) => (PhotometricInterpretation::RGB, &[16, 16, 16, 16], Some(2)),
+        (_, color_type) => {
+            return Err(CaesiumError {
+                message: format!("Unsupported TIFF color type ({color_type:?})"),
+                code: 20506,
+            });
+        }
+    };
+
+    let samples = bits_per_sample.len();
+    let row_bytes = (width as usize * samples * bits_per_sample[0] as usize).div_ceil(8);
+    let mut data = match bits_per_sample[0] {
+        1 => Cow::Owned(pack_bilevel(&page.image.to_luma8())),
+        _ => Cow::Borrowed(page.image.as_bytes()),
+    };
+    let predictor = parameters.tiff.predictor == TiffPredictor::Horizontal
+        && matches!(
+            parameters.tiff.algorithm,
+            TiffCompression::Lzw | TiffCompression::Deflate
+        )
+        && bits_per_sample[0] >= 8;
+    if predictor {
+        horizontal_differencing(data.to_mut(), row_bytes, samples, bits_per_sample[0]);
+    }
+
+    let to_error = |e: TiffError| CaesiumError {
+        message: e.to_string(),
+        code: 20507,
+    };
+    let metadata = Some(&page.metadata).filter(|_| parameters.keep_metadata);
+    let exif = match metadata {
+        Some(metadata) if !metadata.exif.is_empty() => Some(write_exif(encoder, &metadata.exif).map_err(to_error)?),
+        _ => None,
+    };
+    let mut directory = encoder.image_directory().map_err(to_error)?;
+    let mut rows_per_strip = (STRIP_SIZE / row_bytes).max(1);
+    if jpeg {
+        // Every strip but the last must hold whole rows of 16x16 MCUs
+        rows_per_strip = rows_per_strip.next_multiple_of(16);
+    } else if ccitt {
+        // Fax pages are a single stream
+        rows_per_strip = (height as usize).max(1);
+    }
+    let (mut strip_offsets, mut strip_byte_counts) = (vec![], vec![]);
+    for strip in data.chunks(rows_per_strip * row_bytes) {
+        let compressed = compress_strip(strip, width, row_bytes, samples, parameters)?;
+        let offset = directory.write_data(compressed.as_slice()).map_err(to_error)?;
+        strip_offsets.push(u32::try_from(offset).map_err(|e| to_error(e.into()))?);
+        strip_byte_counts.push(compressed.len() as u32);
+    }
+
+    let compression = match parameters.tiff.algorithm {
+        TiffCompression::Uncompressed => CompressionMethod::None,
+        TiffCompression::Lzw => CompressionMethod::LZW,
+        TiffCompression::Deflate => CompressionMethod::Deflate,
+        TiffCompression::Packbits => CompressionMethod::PackBits,
+        TiffCompression::Jpeg => CompressionMethod::ModernJPEG,
+        TiffCompression::CcittGroup3 => CompressionMethod::Fax3,
+        TiffCompression::CcittGroup4 => CompressionMethod::Fax4,
+    };
+    let tags = || -> TiffResult<()> {
+        directory.write_tag(Tag::ImageWidth, width)?;
+        directory.write_tag(Tag::ImageLength, height)?;
+        directory.write_tag(Tag::BitsPerSample, bits_per_sample)?;
+        directory.write_tag(Tag::Compression, compression.to_u16())?;
+        directory.write_tag(Tag::PhotometricInterpretation, photometric.to_u16())?;
+        directory.write_tag(Tag::StripOffsets, strip_offsets.as_slice())?;
+        directory.write_tag(Tag::SamplesPerPixel, samples as u16)?;
+        directory.write_tag(Tag::RowsPerStrip, rows_per_strip as u32)?;
+        directory.write_tag(Tag::StripByteCounts, strip_byte_counts.as_slice())?;
+        let resolution = [Tag::XResolution, Tag::YResolution, Tag::ResolutionUnit];
+        if !metadata.is_some_and(|metadata| resolution.into_iter().any(|tag| metadata.contains(tag))) {
+            directory.write_tag(Tag::XResolution, Rational { n: 1, d: 1 })?;
+            directory.write_tag(Tag::YResolution, Rational { n: 1, d: 1 })?;
+            directory.write_tag(Tag::ResolutionUnit, ResolutionUnit::None.to_u16())?;
+        }
+        for (tag, value) in metadata.iter().flat_map(|metadata| &metadata.tags) {
+            value.write(&mut directory, *tag)?;
+        }
+        if let Some(exif) = exif {
+            directory.write_tag(Tag::ExifDirectory, exif)?;
+        }
+        if parameters.tiff.algorithm == TiffCompression::CcittGroup3 {
+            // One-dimensional coding without fill bits
+            directory.write_tag(T4_OPTIONS, 0u32)?;
+        }
+        if predictor {
+            directory.write_tag(Tag::Predictor, Predictor::Horizontal.to_u16())?;
+        }
+        if let Some(extra_samples) = extra_samples {
+            directory.write_tag(Tag::ExtraSamples, extra_samples)?;
+        }
+        if photometric == PhotometricInterpretation::YCbCr {
+            directory.write_tag(YCBCR_SUBSAMPLING, &[2u16, 2][..])?;
+            let reference = [0, 255, 128, 255, 128, 255].map(|n| Rational { n, d: 1 });
+            directory.write_tag(REFERENCE_BLACK_WHITE, &reference[..])?;
+        }
+        directory.finish()
+    };
+    tags().map_err(to_error)
+}
+
+/// Writes the EXIF entries of a page as a directory outside of the page sequence and returns its offset.
+fn write_exif<W: Write + Seek>(encoder: &mut TiffEncoder<W>, exif: &[(Tag, TagValue)]) -> TiffResult<u32> {
+    let mut directory = encoder.extra_directory()?;
+    for (tag, value) in exif {
+        value.write(&mut directory, *tag)?;
+    }
+    Ok(directory.finish_with_offsets()?.offset)
+}
+
+/// Packs black and white pixels into one bit each, with black as 1 (WhiteIsZero) and every row padded to a byte.
+fn pack_bilevel(image: &GrayImage) -> Vec<u8> {
+    image
+        .rows()
+        .flat_map(|row| {
+            let pixels: Vec<_> = row.collect();
+            pixels
+                .chunks(8)
+                .map(|byte| {
+                    byte.iter()
+                        .enumerate()
+                        .fold(0u8, |acc, (i, p)| if p.0[0] < 128 { acc | 0x80 >> i } else { acc })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Replaces every sample with its difference from the same sample of the previous pixel in the row (predictor 2).
+fn horizontal_differencing(data: &mut [u8], row_bytes: usize, samples: usize, bits_per_sample: u16) {
+    for row in data.chunks_mut(row_bytes) {
+        if bits_per_sample == 16 {
+            let mut values: Vec<u16> = row.chunks_exact(2).map(|v| u16::from_ne_bytes([v[0], v[1]])).collect();
+            for i in (samples..values.len()).rev() {
+                values[i] = values[i].wrapping_sub(values[i - samples]);
             }
-        };
+            row.copy_from_slice(&values.iter().flat_map(|v| v.to_ne_bytes()).collect::<Vec<_>>());
+        } else {
+            for i in (samples..row.len()).rev() {
+                row[i] = row[i].wrapping_sub(row[i - samples]);
+            }
+        }
     }
+}
 
-    let compression_result = match parameters.tiff.algorithm {
-        TiffCompression::Deflate => {
-            write_with_compression!(Deflate::with_level(parse_deflate_level(parameters.tiff.deflate_level)))
+fn compress_strip(
+    strip: &[u8],
+    width: u32,
+    row_bytes: usize,
+    samples: usize,
+    parameters: &CSParameters,
+) -> Result<Vec<u8>, CaesiumError> {
+    let mut compressed = vec![];
+    let result = match parameters.tiff.algorithm {
+        TiffCompression::Uncompressed => {
+            compressed.extend_from_slice(strip);
+            Ok(())
+        }
+        TiffCompression::Lzw => Lzw.write_to(&mut compressed, strip).map(|_| ()),
+        TiffCompression::Deflate => Deflate::with_level(parse_deflate_level(parameters.tiff.deflate_level))
+            .write_to(&mut compressed, strip)
+            .map(|_| ()),
+        // PackBits runs must not cross rows
+        TiffCompression::Packbits => strip
+            .chunks(row_bytes)
+            .try_for_each(|row| Packbits.write_to(&mut compressed, row).map(|_| ())),
+        TiffCompression::CcittGroup3 => return Ok(encode_group3(strip, width as u16, row_bytes)),
+        TiffCompression::CcittGroup4 => return Ok(encode_group4(strip, width as u16, row_bytes)),
+        #[cfg(feature = "jpg")]
+        TiffCompression::Jpeg => {
+            let rows = (strip.len() / row_bytes) as u32;
+            return jpeg::encode_pixels(strip, width, rows, samples as u8, parameters.tiff.jpeg_quality);
+        }
+        #[cfg(not(feature = "jpg"))]
+        TiffCompression::Jpeg => {
+            let _ = (width, samples);
+            return Err(CaesiumError {
+                message: "JPEG compression needs the jpg feature".to_string(),
+                code: 20510,
+            });
         }
-        TiffCompression::Lzw => write_with_compression!(Lzw),
-        TiffCompression::Packbits => write_with_compression!(Packbits),
-        TiffCompression::Uncompressed => write_with_compression!(Uncompressed),
     };
 
-    match compression_result {
-        Ok(_) => Ok(output_stream.get_ref().to_vec()),
-        Err(e) => Err(CaesiumError {
-            message: e.to_string(),
-            code: 20507,
-        }),
+    result.map(|_| compressed).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20507,
+    })
+}
+
+/// Colors of a packed 1-bit row, with black as 1.
+fn pels(row: &[u8], width: u16) -> impl Iterator<Item = Color> + '_ {
+    (0..width as usize).map(|x| match row[x / 8] >> (7 - x % 8) & 1 {
+        1 => Color::Black,
+        _ => Color::White,
+    })
+}
+
+/// Encodes packed 1-bit rows with CCITT T.4 one-dimensional (Modified Huffman) coding. Every row starts with an
+/// EOL and the page ends with RTC (six EOLs).
+fn encode_group3(data: &[u8], width: u16, row_bytes: usize) -> Vec<u8> {
+    let mut writer = VecWriter::new();
+    for row in data.chunks(row_bytes) {
+        write_bits(&mut writer, EOL);
+        // Rows alternate between white and black runs, starting with a possibly empty white one
+        let (mut color, mut run) = (Color::White, 0u16);
+        for pel in pels(row, width) {
+            if pel != color {
+                write_run(&mut writer, color, run);
+                (color, run) = (pel, 0);
+            }
+            run += 1;
+        }
+        write_run(&mut writer, color, run);
+    }
+    (0..6).for_each(|_| write_bits(&mut writer, EOL));
+    writer.finish()
+}
+
+/// Writes a run as makeup codes followed by a terminating code, as in the `fax` crate Group 4 encoder.
+fn write_run(writer: &mut VecWriter, color: Color, mut run: u16) {
+    let codes = match color {
+        Color::White => &white::ENTRIES,
+        Color::Black => &black::ENTRIES,
+    };
+    let mut write = |n: u16| write_bits(writer, codes[if n >= 64 { 63 + n / 64 } else { n } as usize].1);
+    while run >= 2560 {
+        write(2560);
+        run -= 2560;
+    }
+    if run >= 64 {
+        write(run & !63);
+        run &= 63;
+    }
+    write(run);
+}
+
+fn write_bits(writer: &mut VecWriter, bits: Bits) {
+    let Ok(()) = writer.write(bits);
+}
+
+/// Encodes packed 1-bit rows with CCITT T.6 two-dimensional coding, ending with EOFB.
+fn encode_group4(data: &[u8], width: u16, row_bytes: usize) -> Vec<u8> {
+    let mut encoder = Encoder::new(VecWriter::new());
+    for row in data.chunks(row_bytes) {
+        let Ok(()) = encoder.encode_line(pels(row, width), width);
     }
+    let Ok(writer) = encoder.finish();
+    writer.finish()
 }
 
 fn parse_deflate_level(level: TiffDeflateLevel) -> DeflateLevel {
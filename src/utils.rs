@@ -19,6 +19,26 @@ pub fn get_filetype_from_memory(buf: &[u8]) -> SupportedFileTypes {
     }
 }
 
+/// Returns whether a PNG contains an animation control chunk before its image data.
+pub fn is_apng(buf: &[u8]) -> bool {
+    if !buf.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return false;
+    }
+
+    let mut offset = 8;
+    while offset + 8 <= buf.len() {
+        let length = u32::from_be_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]]) as usize;
+        match &buf[offset + 4..offset + 8] {
+            b"acTL" => return true,
+            b"IDAT" | b"IEND" => return false,
+            _ => {}
+        }
+        offset = offset.saturating_add(length).saturating_add(12);
+    }
+
+    false
+}
+
 pub fn get_jpeg_orientation(data: &[u8]) -> u32 {
     let reader = exif::Reader::new();
     let mut cursor = Cursor::new(data);
@@ -1,14 +1,25 @@
+use std::ffi::{c_int, CStr};
 use std::fs::File;
 use std::io::{Read, Write};
+use std::mem::MaybeUninit;
 use std::ops::Deref;
+use std::{ptr, slice};
 
 use bytes::Bytes;
 use image::{DynamicImage, ImageBuffer};
 use img_parts::webp::WebP as PartsWebp;
 use img_parts::{DynImage, ImageEXIF, ImageICC};
-use webp::{AnimDecoder, AnimEncoder, AnimFrame, WebPConfig};
+use libwebp_sys::{
+    WebPAnimEncoder, WebPAnimEncoderAdd, WebPAnimEncoderAssemble, WebPAnimEncoderDelete, WebPAnimEncoderGetError,
+    WebPAnimEncoderNewInternal, WebPAnimEncoderOptions, WebPAnimEncoderOptionsInitInternal, WebPData, WebPDataClear,
+    WebPGetMuxABIVersion, WebPPicture, WebPPictureFree, WebPPictureImportRGBA, WebPPictureInitInternal,
+    WEBP_ENCODER_ABI_VERSION,
+};
+use webp::{AnimDecoder, AnimFrame, DecodeAnimImage, WebPConfig};
 
+use crate::animation::{Animation, AnimationFrame};
 use crate::error::CaesiumError;
+use crate::parameters::WebPImageHint;
 use crate::resize::resize_image;
 use crate::CSParameters;
 
@@ -58,67 +69,11 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
         message: e.to_string(),
         code: 20304,
     })?;
-    let is_animated = frames.has_animation();
 
-    let encoded_image_memory = if is_animated {
-        let mut config = match WebPConfig::new() {
-            Ok(c) => c,
-            Err(_) => {
-                return Err(CaesiumError {
-                    message: "Cannot initialize WebP config".into(),
-                    code: 20309,
-                });
-            }
-        };
-        config.lossless = if parameters.webp.lossless { 1 } else { 0 };
-        config.alpha_compression = if parameters.webp.lossless { 0 } else { 1 };
-        config.quality = parameters.webp.quality as f32;
-
-        let mut images_data = vec![];
-        let mut width = 0;
-        let mut height = 0;
-
-        for (i, f) in frames.into_iter().enumerate() {
-            if must_resize {
-                let mut dyn_image = to_dynamic_image(f);
-                dyn_image = resize_image(dyn_image, parameters.width, parameters.height);
-                if i == 0 {
-                    width = dyn_image.width();
-                    height = dyn_image.height();
-                }
-
-                images_data.push(dyn_image);
-            } else {
-                width = f.width();
-                height = f.height();
-                break;
-            }
-        }
-
-        let mut encoder = AnimEncoder::new(width, height, &config);
-        encoder.set_bgcolor(to_rgba(frames.bg_color));
-        encoder.set_loop_count(frames.loop_count as i32);
-
-        let mut last_ms = 0;
-        for (i, f) in frames.into_iter().enumerate() {
-            let delay_ms = f.get_time_ms() - last_ms;
-            last_ms += delay_ms;
-
-            if must_resize {
-                if images_data.get(i).is_some() {
-                    encoder.add_frame(
-                        AnimFrame::from_image(images_data.get(i).unwrap(), last_ms).map_err(|e| CaesiumError {
-                            message: e.to_string(),
-                            code: 20310,
-                        })?,
-                    );
-                }
-            } else {
-                encoder.add_frame(f);
-            }
-        }
-
-        encoder.encode()
+    let encoded_image = if frames.has_animation() {
+        let mut animation = to_animation(frames);
+        animation.resize(parameters.width, parameters.height);
+        encode_animation(&animation, parameters)?
     } else {
         let first_frame = match frames.get_frame(0) {
             None => {
@@ -134,6 +89,7 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
             input_image = resize_image(input_image, parameters.width, parameters.height);
         }
 
+        let config = webp_config(parameters)?;
         let encoder = match webp::Encoder::from_image(&input_image) {
             Ok(encoder) => encoder,
             Err(e) => {
@@ -144,20 +100,16 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
             }
         };
 
-        if parameters.webp.lossless {
-            if must_resize {
-                encoder.encode(100.0)
-            } else {
-                //TODO With resize can throw an error
-                encoder.encode_lossless()
-            }
-        } else {
-            encoder.encode(parameters.webp.quality as f32)
-        }
+        encoder
+            .encode_advanced(&config)
+            .map_err(|e| CaesiumError {
+                message: format!("{e:?}"),
+                code: 20313,
+            })?
+            .deref()
+            .to_vec()
     };
 
-    let encoded_image = encoded_image_memory.deref().to_vec();
-
     if iccp.is_some() || exif.is_some() {
         let mut image_with_metadata: Vec<u8> = vec![];
         let mut dyn_img = match PartsWebp::from_bytes(encoded_image.clone().into()) {
@@ -180,13 +132,200 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
     }
 }
 
-fn to_rgba(value: u32) -> [u8; 4] {
-    [
-        ((value >> 24) & 0xFF) as u8,
-        ((value >> 16) & 0xFF) as u8,
-        ((value >> 8) & 0xFF) as u8,
-        (value & 0xFF) as u8,
-    ]
+/// Compresses a still lossy WebP with the rate control of libwebp, which converges to the size in a single
+/// multi-pass encode. Returns `None` when it does not apply or misses the size, so that the generic quality
+/// search can be used instead.
+pub fn compress_to_size(
+    in_file: &[u8],
+    parameters: &mut CSParameters,
+    max_output_size: usize,
+) -> Result<Option<Vec<u8>>, CaesiumError> {
+    // The targets override the quality, so they must not be left over for the generic search
+    parameters.webp.target_size = 0;
+    parameters.webp.target_psnr = 0.0;
+
+    // With animations the target would apply to every single frame
+    let is_animated = PartsWebp::from_bytes(Bytes::copy_from_slice(in_file))
+        .map(|webp| webp.has_chunk(*b"ANIM"))
+        .unwrap_or(true);
+    if parameters.webp.lossless || is_animated {
+        return Ok(None);
+    }
+
+    parameters.webp.target_size = max_output_size.min(i32::MAX as usize) as u32;
+    let compressed_file = compress_in_memory(in_file, parameters);
+    parameters.webp.target_size = 0;
+
+    let compressed_file = compressed_file?;
+    Ok((compressed_file.len() <= max_output_size).then_some(compressed_file))
+}
+
+/// Decodes every frame of a WebP, composited on the full canvas.
+pub fn decode_animation(in_file: &[u8]) -> Result<Animation, CaesiumError> {
+    let anim_decoder = AnimDecoder::new(in_file);
+    let frames = anim_decoder.decode().map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20304,
+    })?;
+
+    Ok(to_animation(frames))
+}
+
+fn to_animation(frames: DecodeAnimImage) -> Animation {
+    // The background color is read as a little endian BGRA value
+    let [b, g, r, a] = frames.bg_color.to_le_bytes();
+    let mut animation = Animation {
+        width: 0,
+        height: 0,
+        loop_count: frames.loop_count,
+        background: Some([r, g, b, a]),
+        frames: Vec::with_capacity(frames.len()),
+    };
+
+    // Decoded timestamps are the end time of each frame
+    let mut last_ms = 0;
+    for f in frames.into_iter() {
+        animation.width = f.width();
+        animation.height = f.height();
+        let delay_ms = (f.get_time_ms() - last_ms).max(0) as u32;
+        last_ms = f.get_time_ms();
+        animation.frames.push(AnimationFrame {
+            image: to_dynamic_image(f).to_rgba8(),
+            delay_ms,
+        });
+    }
+
+    animation
+}
+
+/// Encodes full canvas frames with the libwebp animation encoder, which finds the frame rectangles,
+/// blending and disposal by itself.
+pub fn encode_animation(animation: &Animation, parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let config = webp_config(parameters)?;
+    unsafe {
+        let mut options = MaybeUninit::<WebPAnimEncoderOptions>::uninit();
+        if WebPAnimEncoderOptionsInitInternal(options.as_mut_ptr(), WebPGetMuxABIVersion()) == 0 {
+            return Err(CaesiumError {
+                message: "Cannot initialize WebP animation encoder options".into(),
+                code: 20314,
+            });
+        }
+        let mut options = options.assume_init();
+        options.anim_params.loop_count = animation.loop_count.min(u16::MAX as u32) as c_int;
+        options.kmin = parameters.webp.kmin.min(c_int::MAX as u32) as c_int;
+        options.kmax = parameters.webp.kmax.min(c_int::MAX as u32) as c_int;
+        options.allow_mixed = if parameters.webp.allow_mixed { 1 } else { 0 };
+        options.minimize_size = if parameters.webp.minimize_size { 1 } else { 0 };
+        if let Some([r, g, b, a]) = animation.background {
+            options.anim_params.bgcolor = u32::from_le_bytes([b, g, r, a]);
+        }
+
+        let encoder = WebPAnimEncoderNewInternal(
+            animation.width as c_int,
+            animation.height as c_int,
+            &options,
+            WebPGetMuxABIVersion(),
+        );
+        if encoder.is_null() {
+            return Err(CaesiumError {
+                message: "Cannot create WebP animation encoder".into(),
+                code: 20314,
+            });
+        }
+
+        let result = add_frames(encoder, animation, &config);
+        WebPAnimEncoderDelete(encoder);
+        result
+    }
+}
+
+unsafe fn add_frames(
+    encoder: *mut WebPAnimEncoder,
+    animation: &Animation,
+    config: &WebPConfig,
+) -> Result<Vec<u8>, CaesiumError> {
+    // Frames are added with their start time, the final empty frame sets the duration of the last one
+    let mut timestamp: c_int = 0;
+    for frame in animation.frames.iter() {
+        let mut picture = MaybeUninit::<WebPPicture>::uninit();
+        if WebPPictureInitInternal(picture.as_mut_ptr(), WEBP_ENCODER_ABI_VERSION as c_int) == 0 {
+            return Err(CaesiumError {
+                message: "Cannot initialize WebP picture".into(),
+                code: 20315,
+            });
+        }
+        let mut picture = picture.assume_init();
+        picture.use_argb = 1;
+        picture.width = frame.image.width() as c_int;
+        picture.height = frame.image.height() as c_int;
+        let imported = WebPPictureImportRGBA(&mut picture, frame.image.as_ptr(), (frame.image.width() * 4) as c_int);
+        let added = imported != 0 && WebPAnimEncoderAdd(encoder, &mut picture, timestamp, config) != 0;
+        WebPPictureFree(&mut picture);
+        if !added {
+            return Err(anim_encoder_error(encoder));
+        }
+        timestamp = timestamp.saturating_add(frame.delay_ms.min(c_int::MAX as u32) as c_int);
+    }
+
+    let mut webp_data = WebPData::default();
+    if WebPAnimEncoderAdd(encoder, ptr::null_mut(), timestamp, ptr::null()) == 0
+        || WebPAnimEncoderAssemble(encoder, &mut webp_data) == 0
+    {
+        return Err(anim_encoder_error(encoder));
+    }
+    let output = slice::from_raw_parts(webp_data.bytes, webp_data.size).to_vec();
+    WebPDataClear(&mut webp_data);
+
+    Ok(output)
+}
+
+unsafe fn anim_encoder_error(encoder: *mut WebPAnimEncoder) -> CaesiumError {
+    let error = WebPAnimEncoderGetError(encoder);
+    let message = if error.is_null() {
+        "Cannot encode WebP animation".into()
+    } else {
+        CStr::from_ptr(error).to_string_lossy().into_owned()
+    };
+    CaesiumError { message, code: 20312 }
+}
+
+fn webp_config(parameters: &CSParameters) -> Result<WebPConfig, CaesiumError> {
+    let mut config = match WebPConfig::new() {
+        Ok(c) => c,
+        Err(_) => {
+            return Err(CaesiumError {
+                message: "Cannot initialize WebP config".into(),
+                code: 20309,
+            });
+        }
+    };
+    config.lossless = if parameters.webp.lossless { 1 } else { 0 };
+    config.alpha_compression = if parameters.webp.lossless { 0 } else { 1 };
+    config.quality = parameters.webp.quality as f32;
+    config.method = parameters.webp.method as i32;
+    config.sns_strength = parameters.webp.sns_strength as i32;
+    config.filter_strength = parameters.webp.filter_strength as i32;
+    config.filter_sharpness = parameters.webp.filter_sharpness as i32;
+    config.segments = parameters.webp.segments as i32;
+    config.preprocessing = parameters.webp.preprocessing as i32;
+    config.alpha_quality = parameters.webp.alpha_quality as i32;
+    config.near_lossless = parameters.webp.near_lossless as i32;
+    config.exact = if parameters.webp.exact { 1 } else { 0 };
+    config.pass = parameters.webp.pass as i32;
+    config.target_size = parameters.webp.target_size.min(i32::MAX as u32) as i32;
+    config.target_PSNR = parameters.webp.target_psnr;
+    if config.target_size > 0 || config.target_PSNR > 0.0 {
+        // A single pass barely moves away from the starting quality
+        config.pass = config.pass.max(6);
+    }
+    config.image_hint = match parameters.webp.image_hint {
+        WebPImageHint::Default => libwebp_sys::WebPImageHint::WEBP_HINT_DEFAULT,
+        WebPImageHint::Picture => libwebp_sys::WebPImageHint::WEBP_HINT_PICTURE,
+        WebPImageHint::Photo => libwebp_sys::WebPImageHint::WEBP_HINT_PHOTO,
+        WebPImageHint::Graph => libwebp_sys::WebPImageHint::WEBP_HINT_GRAPH,
+    };
+
+    Ok(config)
 }
 
 fn to_dynamic_image(frame: AnimFrame) -> DynamicImage {
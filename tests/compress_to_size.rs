@@ -1,5 +1,6 @@
 use crate::cleanup::remove_compressed_test_file;
 use iodine::parameters::CSParameters;
+use std::fs;
 use std::fs::File;
 use std::sync::Once;
 
@@ -124,3 +125,32 @@ fn compress_to_higher_size_and_resize() {
     assert_eq!(image::image_dimensions(output).unwrap(), (800, 600));
     remove_compressed_test_file(output)
 }
+
+#[test]
+fn compress_webp_to_size() {
+    let in_file = fs::read("tests/samples/uncompressed_家.webp").unwrap();
+    let compressed_size = iodine::compress_in_memory(in_file.clone(), &CSParameters::new())
+        .unwrap()
+        .len();
+    let max_output_size = compressed_size / 2;
+
+    let mut pars = CSParameters::new();
+    let output_buffer = iodine::compress_to_size_in_memory(in_file, &mut pars, max_output_size, false).unwrap();
+
+    assert!(output_buffer.len() <= max_output_size);
+    assert_eq!(infer::get(&output_buffer).unwrap().mime_type(), "image/webp");
+    // Reached by libwebp rate control, without the quality search
+    assert_eq!(80, pars.webp.quality);
+    assert_eq!(0, pars.webp.target_size);
+}
+
+#[test]
+fn webp_target_psnr() {
+    let in_file = fs::read("tests/samples/uncompressed_家.webp").unwrap();
+    let mut pars = CSParameters::new();
+    pars.webp.target_psnr = 30.0;
+    let low = iodine::compress_in_memory(in_file.clone(), &pars).unwrap();
+    pars.webp.target_psnr = 45.0;
+    let high = iodine::compress_in_memory(in_file, &pars).unwrap();
+    assert!(low.len() < high.len());
+}
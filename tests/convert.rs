@@ -1,9 +1,10 @@
-use iodine::parameters::CSParameters;
+use iodine::parameters::{CSParameters, FrameSelection};
 use iodine::SupportedFileTypes;
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 use std::sync::Once;
+use std::time::Duration;
 
 use crate::cleanup::remove_compressed_test_file;
 
@@ -236,6 +237,213 @@ fn convert_tiff_to_webp() {
     remove_compressed_test_file(output)
 }
 
+#[test]
+fn extract_frame() {
+    let output = "tests/samples/output/frame.png";
+    initialize(output);
+    let params = CSParameters::new();
+    iodine::extract_frame(
+        String::from("tests/samples/uncompressed_animated.webp"),
+        String::from(output),
+        &params,
+        SupportedFileTypes::Png,
+        FrameSelection::Index(3),
+    )
+    .unwrap();
+    assert_eq!(infer::get_from_path(output).unwrap().unwrap().mime_type(), "image/png");
+    remove_compressed_test_file(output)
+}
+
+#[test]
+fn extract_frame_selection() {
+    let colors = [
+        [0, 0, 0],
+        [120, 120, 120],
+        [130, 130, 130],
+        [125, 125, 125],
+        [255, 255, 255],
+    ];
+    let mut gif_file = vec![];
+    {
+        let mut encoder = gif::Encoder::new(&mut gif_file, 8, 8, &[]).unwrap();
+        for color in colors {
+            let mut pixels: Vec<u8> = (0..64).flat_map(|_| [color[0], color[1], color[2], 255]).collect();
+            let mut frame = gif::Frame::from_rgba_speed(8, 8, &mut pixels, 10);
+            frame.delay = 10;
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    let first_pixel = |frame: FrameSelection| {
+        let still = iodine::extract_frame_in_memory(gif_file.clone(), &params, SupportedFileTypes::Png, frame).unwrap();
+        let image = image::load_from_memory(&still).unwrap().to_rgb8();
+        image.get_pixel(0, 0).0
+    };
+
+    assert_eq!(first_pixel(FrameSelection::Index(0)), colors[0]);
+    assert_eq!(first_pixel(FrameSelection::Index(4)), colors[4]);
+    assert_eq!(first_pixel(FrameSelection::Time(Duration::from_millis(250))), colors[2]);
+    assert_eq!(first_pixel(FrameSelection::Representative), colors[3]);
+
+    let jpeg = iodine::extract_frame_in_memory(
+        gif_file.clone(),
+        &params,
+        SupportedFileTypes::Jpeg,
+        FrameSelection::Index(1),
+    )
+    .unwrap();
+    assert_eq!(infer::get(&jpeg).unwrap().mime_type(), "image/jpeg");
+
+    let err = iodine::extract_frame_in_memory(
+        gif_file.clone(),
+        &params,
+        SupportedFileTypes::Png,
+        FrameSelection::Index(5),
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.code, 10415);
+    let err = iodine::extract_frame_in_memory(
+        gif_file,
+        &params,
+        SupportedFileTypes::Png,
+        FrameSelection::Time(Duration::from_secs(1)),
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.code, 10416);
+}
+
+#[test]
+fn extract_frame_without_frames() {
+    let mut gif_file = vec![];
+    {
+        let mut encoder = gif::Encoder::new(&mut gif_file, 8, 8, &[]).unwrap();
+        encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+    }
+    let params = CSParameters::new();
+
+    for frame in [
+        FrameSelection::Representative,
+        FrameSelection::Index(0),
+        FrameSelection::Time(Duration::ZERO),
+    ] {
+        let err = iodine::extract_frame_in_memory(gif_file.clone(), &params, SupportedFileTypes::Png, frame)
+            .err()
+            .unwrap();
+        assert_eq!(err.code, 10415);
+    }
+}
+
+#[test]
+fn convert_animated_gif_to_webp() {
+    let gif_file = moving_square_gif(gif::Repeat::Finite(3));
+    let webp_file = iodine::convert_in_memory(gif_file, &CSParameters::new(), SupportedFileTypes::WebP, false).unwrap();
+    let frames = webp::AnimDecoder::new(&webp_file).decode().unwrap();
+    assert_eq!(frames.loop_count, 4);
+    let first_frame = frames.get_frame(0).unwrap();
+    assert_eq!(first_frame.get_image()[3], 255);
+    assert_eq!(first_frame.get_image()[(15 * 16 + 15) * 4 + 3], 0);
+    let timestamps: Vec<i32> = frames.into_iter().map(|f| f.get_time_ms()).collect();
+    assert_eq!(timestamps, vec![50, 110, 180, 260]);
+}
+
+#[test]
+fn gif_loop_count_round_trip() {
+    // NETSCAPE loop counts are repeats after the first play, WebP and APNG count every play
+    for (repeat, plays) in [
+        (gif::Repeat::Infinite, 0),
+        (gif::Repeat::Finite(0), 1),
+        (gif::Repeat::Finite(3), 4),
+    ] {
+        let webp_file = iodine::convert_in_memory(
+            moving_square_gif(repeat),
+            &CSParameters::new(),
+            SupportedFileTypes::WebP,
+            false,
+        )
+        .unwrap();
+        assert_eq!(webp::AnimDecoder::new(&webp_file).decode().unwrap().loop_count, plays);
+        let gif_file =
+            iodine::convert_in_memory(webp_file, &CSParameters::new(), SupportedFileTypes::Gif, false).unwrap();
+        assert_eq!(gif_repeat(&gif_file), repeat);
+
+        let apng_file = iodine::convert_in_memory(
+            moving_square_gif(repeat),
+            &CSParameters::new(),
+            SupportedFileTypes::Png,
+            false,
+        )
+        .unwrap();
+        let apng = png::Decoder::new(std::io::Cursor::new(&apng_file)).read_info().unwrap();
+        assert_eq!(apng.info().animation_control.unwrap().num_plays, plays);
+        let gif_file =
+            iodine::convert_in_memory(apng_file, &CSParameters::new(), SupportedFileTypes::Gif, false).unwrap();
+        assert_eq!(gif_repeat(&gif_file), repeat);
+    }
+}
+
+#[test]
+fn convert_animated_webp_to_gif() {
+    let in_file = fs::read("tests/samples/uncompressed_animated.webp").unwrap();
+    let frames = webp::AnimDecoder::new(&in_file).decode().unwrap();
+    let duration_ms = frames.into_iter().last().unwrap().get_time_ms();
+
+    let gif_file = iodine::convert_in_memory(in_file, &CSParameters::new(), SupportedFileTypes::Gif, false).unwrap();
+    let mut decoder = gif::DecodeOptions::new().read_info(gif_file.as_slice()).unwrap();
+    let mut gif_duration_ms = 0;
+    let mut gif_frames = 0;
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        gif_duration_ms += frame.delay as i32 * 10;
+        gif_frames += 1;
+    }
+    assert!(gif_frames > 1);
+    assert!((gif_duration_ms - duration_ms).abs() <= 10 * gif_frames);
+    assert_eq!(decoder.repeat(), gif::Repeat::Infinite);
+
+    // A WebP played twice repeats once after the first play
+    let (red, blue) = ([[255u8, 0, 0, 255]; 64].concat(), [[0u8, 0, 255, 255]; 64].concat());
+    let config = webp::WebPConfig::new().unwrap();
+    let mut encoder = webp::AnimEncoder::new(8, 8, &config);
+    encoder.set_loop_count(2);
+    encoder.add_frame(webp::AnimFrame::from_rgba(&red, 8, 8, 0));
+    encoder.add_frame(webp::AnimFrame::from_rgba(&blue, 8, 8, 100));
+    let webp_file = encoder.encode().to_vec();
+    let gif_file = iodine::convert_in_memory(webp_file, &CSParameters::new(), SupportedFileTypes::Gif, false).unwrap();
+    assert_eq!(gif_repeat(&gif_file), gif::Repeat::Finite(1));
+}
+
+/// A 16x16 GIF of a square moving over a transparent background in 4 frames.
+fn moving_square_gif(repeat: gif::Repeat) -> Vec<u8> {
+    let mut gif_file = vec![];
+    {
+        let mut encoder = gif::Encoder::new(&mut gif_file, 16, 16, &[]).unwrap();
+        encoder.set_repeat(repeat).unwrap();
+        for i in 0..4u8 {
+            let mut pixels: Vec<u8> = (0..256)
+                .flat_map(|p| {
+                    if (p % 16) / 4 == i as usize && p / 16 < 4 {
+                        [255, 60 * i, 0, 255]
+                    } else {
+                        [0, 0, 0, 0]
+                    }
+                })
+                .collect();
+            let mut frame = gif::Frame::from_rgba_speed(16, 16, &mut pixels, 10);
+            frame.delay = 5 + i as u16;
+            frame.dispose = gif::DisposalMethod::Background;
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    gif_file
+}
+
+fn gif_repeat(gif_file: &[u8]) -> gif::Repeat {
+    gif::DecodeOptions::new().read_info(gif_file).unwrap().repeat()
+}
+
 fn extract_exif(path: &Path) -> HashMap<String, String> {
     let file = fs::File::open(path).unwrap();
     let mut bufreader = std::io::BufReader::new(&file);
@@ -2,6 +2,7 @@ use crate::cleanup::remove_compressed_test_file;
 use iodine::parameters::CSParameters;
 use std::fs::File;
 use std::sync::Once;
+use std::time::Duration;
 
 mod cleanup;
 
@@ -117,3 +118,279 @@ fn downscale_to_size() {
     assert_eq!(image::image_dimensions(output).unwrap(), (150, 100));
     remove_compressed_test_file(output)
 }
+
+#[test]
+fn lossy_and_motion_quality() {
+    let mut params = CSParameters::new();
+    params.width = 150;
+    let in_file = std::fs::read("tests/samples/uncompressed_은하.gif").unwrap();
+    let default_size = iodine::compress_in_memory(in_file.clone(), &params).unwrap().len();
+
+    params.gif.lossy_quality = 20;
+    params.gif.motion_quality = 20;
+    let lossy_size = iodine::compress_in_memory(in_file.clone(), &params).unwrap().len();
+    assert!(lossy_size < default_size);
+
+    params.gif.fast = true;
+    params.gif.extra_effort = true;
+    let compressed = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert_eq!(infer::get(&compressed).unwrap().mime_type(), "image/gif");
+
+    params.gif.lossy_quality = 101;
+    let err = iodine::compress_in_memory(in_file, &params).err().unwrap();
+    assert_eq!(err.code, 10017);
+}
+
+#[test]
+fn compress_to_size_with_lossy_quality() {
+    let mut params = CSParameters::new();
+    params.width = 150;
+    let in_file = std::fs::read("tests/samples/uncompressed_은하.gif").unwrap();
+    let default_size = iodine::compress_in_memory(in_file.clone(), &params).unwrap().len();
+
+    let max_output_size = default_size * 8 / 10;
+    let compressed = iodine::compress_to_size_in_memory(in_file, &mut params, max_output_size, false).unwrap();
+    assert!(compressed.len() <= max_output_size);
+    assert_eq!(params.gif.quality, 80);
+    assert!((1..80).contains(&params.gif.lossy_quality));
+    assert_eq!(params.gif.lossy_quality, params.gif.motion_quality);
+}
+
+fn animated_gif(delays: &[u16]) -> Vec<u8> {
+    let mut out = vec![];
+    {
+        let mut encoder = gif::Encoder::new(&mut out, 8, 8, &[]).unwrap();
+        encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+        for (i, delay) in delays.iter().enumerate() {
+            let mut pixels = vec![0u8; 8 * 8 * 4];
+            for (j, px) in pixels.chunks_exact_mut(4).enumerate() {
+                px.copy_from_slice(&[(i * 60) as u8, (j * 4) as u8, 255 - (i * 60) as u8, 255]);
+            }
+            let mut frame = gif::Frame::from_rgba_speed(8, 8, &mut pixels, 10);
+            frame.delay = *delay;
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+    out
+}
+
+fn frame_delays(gif_file: &[u8]) -> Vec<u16> {
+    let mut decoder = gif::DecodeOptions::new().read_info(gif_file).unwrap();
+    let mut delays = vec![];
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        delays.push(frame.delay);
+    }
+    delays
+}
+
+#[test]
+fn preserve_delays() {
+    let in_file = animated_gif(&[5, 0, 7, 3]);
+    let mut params = CSParameters::new();
+
+    let compressed = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert_eq!(frame_delays(&compressed), vec![5, 10, 7, 7]);
+
+    params.gif.preserve_delays = true;
+    let compressed = iodine::compress_in_memory(in_file, &params).unwrap();
+    assert_eq!(frame_delays(&compressed), vec![5, 0, 7, 3]);
+
+    // gifski cannot write delays under 20 ms by itself
+    let in_file = animated_gif(&[1, 2, 1, 0]);
+    let compressed = iodine::compress_in_memory(in_file, &params).unwrap();
+    assert_eq!(frame_delays(&compressed), vec![1, 2, 1, 0]);
+}
+
+/// Composited frames with their delays, identical consecutive frames merged.
+fn timeline(gif_file: &[u8]) -> Vec<(Vec<u8>, u32)> {
+    use image::AnimationDecoder;
+
+    let decoder = image::codecs::gif::GifDecoder::new(std::io::Cursor::new(gif_file)).unwrap();
+    let mut timeline: Vec<(Vec<u8>, u32)> = vec![];
+    for frame in decoder.into_frames() {
+        let frame = frame.unwrap();
+        let (numerator, _) = frame.delay().numer_denom_ms();
+        let mut pixels = frame.into_buffer().into_raw();
+        for px in pixels.chunks_exact_mut(4) {
+            if px[3] == 0 {
+                px.copy_from_slice(&[0, 0, 0, 0]);
+            }
+        }
+        match timeline.last_mut() {
+            Some((last, delay)) if *last == pixels => *delay += numerator,
+            _ => timeline.push((pixels, numerator)),
+        }
+    }
+    timeline
+}
+
+#[test]
+fn optimize() {
+    let in_file = std::fs::read("tests/samples/uncompressed_은하.gif").unwrap();
+    let mut params = CSParameters::new();
+    params.gif.optimize = true;
+
+    let compressed = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert!(compressed.len() <= in_file.len());
+    assert!(timeline(&compressed) == timeline(&in_file));
+}
+
+#[test]
+fn optimize_transparency() {
+    let mut out = vec![];
+    {
+        let mut encoder = gif::Encoder::new(&mut out, 16, 16, &[]).unwrap();
+        encoder.set_repeat(gif::Repeat::Finite(2)).unwrap();
+        for i in 0..6u8 {
+            let mut pixels = vec![0u8; 16 * 16 * 4];
+            for (j, px) in pixels.chunks_exact_mut(4).enumerate() {
+                let (x, y) = (j % 16, j / 16);
+                // A square moving over a static background, with a transparent hole every other frame
+                if x >= i as usize * 2 && x < i as usize * 2 + 4 && y < 4 {
+                    px.copy_from_slice(&[255, 0, 0, 255]);
+                } else if i % 2 == 1 && y > 12 {
+                    px.copy_from_slice(&[0, 0, 0, 0]);
+                } else {
+                    px.copy_from_slice(&[0, (x * 16) as u8, (y * 16) as u8, 255]);
+                }
+            }
+            let mut frame = gif::Frame::from_rgba_speed(16, 16, &mut pixels, 10);
+            frame.delay = if i == 2 { 0 } else { 4 };
+            encoder.write_frame(&frame).unwrap();
+        }
+        // Same frame again, merged into the previous one
+        let mut pixels = vec![255u8; 16 * 16 * 4];
+        let mut frame = gif::Frame::from_rgba_speed(16, 16, &mut pixels, 10);
+        frame.delay = 3;
+        encoder.write_frame(&frame).unwrap();
+        encoder.write_frame(&frame).unwrap();
+    }
+
+    let mut params = CSParameters::new();
+    params.gif.optimize = true;
+    let compressed = iodine::compress_in_memory(out.clone(), &params).unwrap();
+    assert!(compressed.len() < out.len());
+    assert!(timeline(&compressed) == timeline(&out));
+
+    let decoder = gif::DecodeOptions::new().read_info(compressed.as_slice()).unwrap();
+    assert_eq!(decoder.repeat(), gif::Repeat::Finite(2));
+}
+
+#[test]
+fn frame_rate_and_count_limits() {
+    let in_file = animated_gif(&[5; 20]);
+    let mut params = CSParameters::new();
+
+    params.gif.max_frame_rate = 5;
+    let compressed = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    let delays = frame_delays(&compressed);
+    assert_eq!(delays.len(), 5);
+    assert_eq!(delays.iter().sum::<u16>(), 100);
+
+    params.gif.max_frame_rate = 0;
+    params.gif.max_frames = 4;
+    let compressed = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    let delays = frame_delays(&compressed);
+    assert_eq!(delays.len(), 4);
+    assert_eq!(delays.iter().sum::<u16>(), 100);
+}
+
+#[test]
+fn trim() {
+    let in_file = animated_gif(&[5; 20]);
+    let mut params = CSParameters::new();
+    params.gif.trim_start = Some(Duration::from_millis(200));
+    params.gif.trim_end = Some(Duration::from_millis(500));
+    let compressed = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert_eq!(frame_delays(&compressed), vec![5; 6]);
+
+    params.gif.trim_start = Some(Duration::from_secs(5));
+    params.gif.trim_end = None;
+    let err = iodine::compress_in_memory(in_file.clone(), &params).err().unwrap();
+    assert_eq!(err.code, 20413);
+
+    params.gif.trim_start = Some(Duration::from_millis(500));
+    params.gif.trim_end = Some(Duration::from_millis(200));
+    let err = iodine::compress_in_memory(in_file, &params).err().unwrap();
+    assert_eq!(err.code, 10018);
+}
+
+#[test]
+fn keep_extensions() {
+    let mut in_file = animated_gif(&[5, 5, 5]);
+    // Replace the loop extension written by the encoder
+    let header_end = 13 + (3 << ((in_file[10] & 0x07) + 1));
+    assert_eq!(&in_file[header_end + 3..header_end + 14], b"NETSCAPE2.0");
+    in_file.drain(header_end..header_end + 19);
+    let mut extensions = vec![0x21, 0xFE, 12];
+    extensions.extend_from_slice(b"By Jane Doe.");
+    extensions.push(0);
+    extensions.extend_from_slice(&[0x21, 0xFF, 11]);
+    extensions.extend_from_slice(b"XMP DataXMP");
+    let xmp = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>";
+    extensions.extend_from_slice(xmp);
+    extensions.push(0x01);
+    extensions.extend((0..=0xFF).rev());
+    extensions.push(0);
+    extensions.extend_from_slice(&[0x21, 0xFF, 11]);
+    extensions.extend_from_slice(b"NETSCAPE2.0");
+    extensions.extend_from_slice(&[3, 1, 4, 0, 0]);
+    in_file.splice(header_end..header_end, extensions);
+
+    let info = iodine::probe_in_memory(in_file.clone()).unwrap();
+    assert_eq!(info.gif_comments, vec!["By Jane Doe.".to_string()]);
+    assert_eq!(info.gif_loop_count, Some(4));
+    assert_eq!(info.gif_application_extensions.len(), 2);
+    assert_eq!(info.gif_application_extensions[0].identifier, "XMP Data");
+    assert_eq!(&info.gif_application_extensions[0].authentication_code, b"XMP");
+    assert!(info.gif_application_extensions[0].data.starts_with(xmp));
+
+    let mut params = CSParameters::new();
+    let compressed = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    let compressed_info = iodine::probe_in_memory(compressed).unwrap();
+    assert!(!compressed_info.gif_comments.contains(&info.gif_comments[0]));
+    assert_eq!(compressed_info.gif_application_extensions.len(), 1);
+
+    for optimize in [false, true] {
+        params.gif.keep_extensions = true;
+        params.gif.optimize = optimize;
+        let compressed = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+        let compressed_info = iodine::probe_in_memory(compressed.clone()).unwrap();
+        assert_eq!(compressed_info.gif_comments, info.gif_comments);
+        assert_eq!(
+            compressed_info.gif_application_extensions,
+            info.gif_application_extensions
+        );
+        assert_eq!(compressed_info.gif_loop_count, Some(4));
+        assert_eq!(frame_delays(&compressed).len(), 3);
+    }
+}
+
+#[test]
+fn keep_extensions_position() {
+    let graphic_control = [0x21, 0xF9, 0x04];
+    let positions = |data: &[u8], pattern: &[u8]| -> Vec<usize> {
+        data.windows(pattern.len())
+            .enumerate()
+            .filter(|(_, w)| *w == pattern)
+            .map(|(i, _)| i)
+            .collect()
+    };
+
+    let mut in_file = animated_gif(&[5, 5]);
+    let mut comment = vec![0x21, 0xFE, 12];
+    comment.extend_from_slice(b"Second frame");
+    comment.push(0);
+    let second_frame = positions(&in_file, &graphic_control)[1];
+    in_file.splice(second_frame..second_frame, comment.clone());
+
+    let mut params = CSParameters::new();
+    params.gif.keep_extensions = true;
+    for optimize in [false, true] {
+        params.gif.optimize = optimize;
+        let compressed = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+        let frames = positions(&compressed, &graphic_control);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(positions(&compressed, &comment), vec![frames[1] - comment.len()]);
+    }
+}
@@ -1,7 +1,7 @@
 use crate::cleanup::remove_compressed_test_file;
 use dssim::Val;
 use img_parts::ImageICC;
-use iodine::parameters::CSParameters;
+use iodine::parameters::{CSParameters, JpegScan};
 use std::{fs, fs::File, sync::Once};
 
 mod cleanup;
@@ -14,6 +14,10 @@ pub fn initialize(file: &str) {
     });
 }
 
+fn count_markers(buffer: &[u8], marker: u8) -> usize {
+    buffer.windows(2).filter(|w| w[0] == 0xFF && w[1] == marker).count()
+}
+
 fn diff(compressed: &str) -> Val {
     let attr = dssim::Dssim::new();
     let orig = dssim::load_image(&attr, "tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
@@ -274,3 +278,110 @@ fn preserve_icc() {
 
     assert!(output_iccp.is_none());
 }
+
+#[test]
+fn arithmetic_coding() {
+    let mut pars = CSParameters::new();
+    pars.jpeg.arithmetic_coding = true;
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+
+    // lossy
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &pars).unwrap();
+    let output = img_parts::jpeg::Jpeg::from_bytes(output_buffer.into()).unwrap();
+    assert!(output.segment_by_marker(img_parts::jpeg::markers::SOF10).is_some());
+
+    // lossless
+    pars.jpeg.optimize = true;
+    let output_buffer = iodine::compress_in_memory(in_file, &pars).unwrap();
+    let output = img_parts::jpeg::Jpeg::from_bytes(output_buffer.into()).unwrap();
+    assert!(output.segment_by_marker(img_parts::jpeg::markers::SOF10).is_some());
+}
+
+#[test]
+fn restart_interval() {
+    let mut pars = CSParameters::new();
+    pars.jpeg.restart_in_rows = 1;
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+
+    // lossy
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &pars).unwrap();
+    let output = img_parts::jpeg::Jpeg::from_bytes(output_buffer.into()).unwrap();
+    assert!(output.segment_by_marker(img_parts::jpeg::markers::DRI).is_some());
+
+    // lossless
+    pars.jpeg.optimize = true;
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &pars).unwrap();
+    let output = img_parts::jpeg::Jpeg::from_bytes(output_buffer.into()).unwrap();
+    assert!(output.segment_by_marker(img_parts::jpeg::markers::DRI).is_some());
+
+    pars.jpeg.restart_interval = 70000;
+    let err = iodine::compress_in_memory(in_file.clone(), &pars).err().unwrap();
+    assert_eq!(err.code, 10007);
+
+    pars.jpeg.restart_interval = 0;
+    pars.jpeg.restart_in_rows = 70000;
+    let err = iodine::compress_in_memory(in_file, &pars).err().unwrap();
+    assert_eq!(err.code, 10007);
+}
+
+#[test]
+fn custom_scan_script() {
+    let mut pars = CSParameters::new();
+    let scans = [
+        (3, [0, 1, 2, 0], 0, 0),
+        (1, [0, 0, 0, 0], 1, 5),
+        (1, [1, 0, 0, 0], 1, 63),
+        (1, [2, 0, 0, 0], 1, 63),
+        (1, [0, 0, 0, 0], 6, 63),
+    ];
+    for (comps_in_scan, component_index, ss, se) in scans {
+        assert!(pars.jpeg.scan_script.push(JpegScan {
+            comps_in_scan,
+            component_index,
+            ss,
+            se,
+            ah: 0,
+            al: 0,
+        }));
+    }
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+
+    // lossy
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert_eq!(
+        count_markers(&output_buffer, img_parts::jpeg::markers::SOS),
+        scans.len()
+    );
+
+    // lossless
+    pars.jpeg.optimize = true;
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert_eq!(
+        count_markers(&output_buffer, img_parts::jpeg::markers::SOS),
+        scans.len()
+    );
+
+    // invalid
+    pars.jpeg.scan_script.push(JpegScan {
+        comps_in_scan: 1,
+        component_index: [0, 0, 0, 0],
+        ss: 10,
+        se: 5,
+        ah: 0,
+        al: 0,
+    });
+    assert!(iodine::compress_in_memory(in_file, &pars).is_err());
+}
+
+#[test]
+fn standard_progression_without_scan_optimization() {
+    let mut pars = CSParameters::new();
+    pars.jpeg.optimize_scans = false;
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+
+    let output_buffer = iodine::compress_in_memory(in_file, &pars).unwrap();
+    let output = img_parts::jpeg::Jpeg::from_bytes(output_buffer.clone().into()).unwrap();
+    assert!(output.segment_by_marker(img_parts::jpeg::markers::SOF2).is_some());
+    let decoded = image::load_from_memory(&output_buffer).unwrap();
+    assert_eq!((decoded.width(), decoded.height()), (2400, 1600));
+}
@@ -1,6 +1,9 @@
 use crate::cleanup::remove_compressed_test_file;
-use iodine::parameters::CSParameters;
-use std::{fs::File, sync::Once};
+use iodine::parameters::{CSParameters, PngHighBitDepth, PngInterlace, PngQualityFallback, PngTextChunk};
+use iodine::SupportedFileTypes;
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::{fs, fs::File, sync::Once};
 
 mod cleanup;
 
@@ -149,3 +152,335 @@ fn downscale_to_size() {
     assert_eq!(image::image_dimensions(output).unwrap(), (150, 150));
     remove_compressed_test_file(output)
 }
+
+#[test]
+fn max_colors() {
+    let mut params = CSParameters::new();
+    params.png.max_colors = 16;
+    params.png.dithering_level = 0.0;
+    params.png.speed = 10;
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    let output_buffer = iodine::compress_in_memory(in_file, &params).unwrap();
+    assert_eq!(infer::get(&output_buffer).unwrap().mime_type(), "image/png");
+    let colors: HashSet<_> = image::load_from_memory(&output_buffer)
+        .unwrap()
+        .to_rgba8()
+        .pixels()
+        .copied()
+        .collect();
+    assert!(colors.len() <= 16);
+}
+
+#[test]
+fn quality_too_low() {
+    let mut params = CSParameters::new();
+    params.png.max_colors = 2;
+    params.png.min_quality = 80;
+    params.png.quality = 100;
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+
+    params.png.quality_fallback = PngQualityFallback::Error;
+    assert!(iodine::compress_in_memory(in_file.clone(), &params).is_err());
+
+    params.png.quality_fallback = PngQualityFallback::Lossless;
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert_eq!(infer::get(&output_buffer).unwrap().mime_type(), "image/png");
+    let original = image::load_from_memory(&in_file).unwrap().to_rgba8();
+    let compressed = image::load_from_memory(&output_buffer).unwrap().to_rgba8();
+    assert_eq!(original, compressed);
+}
+
+#[test]
+fn optimize_lossy() {
+    let mut params = CSParameters::new();
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+
+    params.png.optimize_lossy = false;
+    let lossy = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    params.png.optimize_lossy = true;
+    let optimized = iodine::compress_in_memory(in_file, &params).unwrap();
+
+    assert_eq!(infer::get(&optimized).unwrap().mime_type(), "image/png");
+    assert!(optimized.len() <= lossy.len());
+    let lossy = image::load_from_memory(&lossy).unwrap().to_rgba8();
+    let optimized = image::load_from_memory(&optimized).unwrap().to_rgba8();
+    assert_eq!(lossy, optimized);
+}
+
+fn encode_png(image: image::DynamicImage) -> Vec<u8> {
+    let mut buffer = vec![];
+    image
+        .write_to(&mut Cursor::new(&mut buffer), image::ImageFormat::Png)
+        .unwrap();
+    buffer
+}
+
+#[test]
+fn high_bit_depth() {
+    let image = image::ImageBuffer::from_fn(64, 64, |x, y| {
+        image::Rgb([(x * 1000) as u16, (y * 1000) as u16, 12345u16])
+    });
+    let in_file = encode_png(image::DynamicImage::ImageRgb16(image));
+    let mut params = CSParameters::new();
+
+    params.png.high_bit_depth = PngHighBitDepth::Lossless;
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert_eq!(
+        image::load_from_memory(&output_buffer).unwrap().color(),
+        image::ColorType::Rgb16
+    );
+
+    params.png.high_bit_depth = PngHighBitDepth::Error;
+    assert!(iodine::compress_in_memory(in_file.clone(), &params).is_err());
+
+    params.png.high_bit_depth = PngHighBitDepth::Quantize;
+    let output_buffer = iodine::compress_in_memory(in_file, &params).unwrap();
+    assert_ne!(
+        image::load_from_memory(&output_buffer).unwrap().color(),
+        image::ColorType::Rgb16
+    );
+}
+
+#[test]
+fn disabled_reductions() {
+    let image = image::ImageBuffer::from_fn(64, 64, |x, _| image::Luma([if x % 2 == 0 { 0u8 } else { 255u8 }]));
+    let in_file = encode_png(image::DynamicImage::ImageLuma8(image));
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    // bit depth is reduced to 1
+    assert_eq!(output_buffer[24], 1);
+
+    params.png.reductions.bit_depth = false;
+    params.png.reductions.color_type = false;
+    params.png.reductions.palette = false;
+    params.png.reductions.grayscale = false;
+    let output_buffer = iodine::compress_in_memory(in_file, &params).unwrap();
+    assert_eq!(output_buffer[24], 8);
+    assert_eq!(
+        image::load_from_memory(&output_buffer).unwrap().color(),
+        image::ColorType::L8
+    );
+}
+
+fn apng_frames(buffer: &[u8]) -> usize {
+    use image::AnimationDecoder;
+    let decoder = image::codecs::png::PngDecoder::new(Cursor::new(buffer)).unwrap();
+    assert!(decoder.is_apng().unwrap());
+    decoder.apng().unwrap().into_frames().collect_frames().unwrap().len()
+}
+
+fn gif_to_apng(params: &CSParameters) -> Vec<u8> {
+    let in_file = fs::read("tests/samples/uncompressed_은하.gif").unwrap();
+    iodine::convert_in_memory(in_file, params, SupportedFileTypes::Png, false).unwrap()
+}
+
+#[test]
+fn convert_gif_to_apng() {
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    let output_buffer = gif_to_apng(&params);
+    assert_eq!(infer::get(&output_buffer).unwrap().mime_type(), "image/png");
+    assert!(apng_frames(&output_buffer) > 1);
+}
+
+#[test]
+fn compress_apng() {
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    let in_file = gif_to_apng(&params);
+    let frames = apng_frames(&in_file);
+
+    let lossless = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert_eq!(apng_frames(&lossless), frames);
+
+    params.png.optimize = false;
+    params.png.quality = 60;
+    let lossy = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert_eq!(apng_frames(&lossy), frames);
+
+    params.width = 100;
+    let resized = iodine::compress_in_memory(in_file, &params).unwrap();
+    assert_eq!(apng_frames(&resized), frames);
+    assert_eq!(image::load_from_memory(&resized).unwrap().width(), 100);
+}
+
+#[test]
+fn convert_apng_to_animated() {
+    let in_file = gif_to_apng(&CSParameters::new());
+    let frames = apng_frames(&in_file);
+
+    let webp =
+        iodine::convert_in_memory(in_file.clone(), &CSParameters::new(), SupportedFileTypes::WebP, false).unwrap();
+    assert_eq!(infer::get(&webp).unwrap().mime_type(), "image/webp");
+    assert!(image::codecs::webp::WebPDecoder::new(Cursor::new(&webp))
+        .unwrap()
+        .has_animation());
+
+    let gif = iodine::convert_in_memory(in_file, &CSParameters::new(), SupportedFileTypes::Gif, false).unwrap();
+    assert_eq!(infer::get(&gif).unwrap().mime_type(), "image/gif");
+    let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(&gif)).unwrap();
+    use image::AnimationDecoder;
+    assert_eq!(decoder.into_frames().collect_frames().unwrap().len(), frames);
+}
+
+#[test]
+fn convert_animated_webp_to_apng() {
+    let in_file = fs::read("tests/samples/uncompressed_animated.webp").unwrap();
+    let output_buffer =
+        iodine::convert_in_memory(in_file, &CSParameters::new(), SupportedFileTypes::Png, false).unwrap();
+    assert!(apng_frames(&output_buffer) > 1);
+}
+
+fn text_chunks(buffer: Vec<u8>) -> Vec<PngTextChunk> {
+    let mut text = iodine::probe_in_memory(buffer).unwrap().png_text;
+    text.sort_by(|a, b| a.keyword.cmp(&b.keyword));
+    text
+}
+
+#[test]
+fn text_chunks_handling() {
+    let mut params = CSParameters::new();
+    params.png.text_chunks = vec![
+        PngTextChunk::new("Author", "드림캐쳐"),
+        PngTextChunk::new("Software", "editor"),
+        PngTextChunk::new("Description", "sample"),
+    ];
+    let in_file =
+        iodine::compress_in_memory(fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap(), &params).unwrap();
+    assert_eq!(text_chunks(in_file.clone()).len(), 3);
+
+    for optimize in [false, true] {
+        let mut params = CSParameters::new();
+        params.png.optimize = optimize;
+        let output_buffer = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+        assert!(text_chunks(output_buffer).is_empty());
+
+        params.png.keep_text_keys = vec!["Author".into(), "Software".into()];
+        params.png.text_chunks = vec![
+            PngTextChunk::new("Software", "iodine"),
+            PngTextChunk::new("Source", "tests/samples"),
+        ];
+        let output_buffer = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+        assert_eq!(
+            text_chunks(output_buffer),
+            vec![
+                PngTextChunk::new("Author", "드림캐쳐"),
+                PngTextChunk::new("Software", "iodine"),
+                PngTextChunk::new("Source", "tests/samples"),
+            ]
+        );
+
+        params.keep_metadata = true;
+        params.png.text_chunks.clear();
+        let output_buffer = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+        assert_eq!(text_chunks(output_buffer), text_chunks(in_file.clone()));
+    }
+}
+
+#[test]
+fn invalid_text_keyword() {
+    let mut params = CSParameters::new();
+    params.png.text_chunks = vec![PngTextChunk::new("", "empty")];
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    assert!(iodine::compress_in_memory(in_file, &params).is_err());
+}
+
+fn is_interlaced(buffer: &[u8]) -> bool {
+    buffer[28] == 1
+}
+
+#[test]
+fn interlace() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    assert!(!is_interlaced(&in_file));
+
+    for optimize in [false, true] {
+        let mut params = CSParameters::new();
+        params.png.optimize = optimize;
+
+        params.png.interlace = PngInterlace::Force;
+        let interlaced = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+        assert!(is_interlaced(&interlaced));
+        let image = image::load_from_memory(&interlaced).unwrap();
+        assert_eq!((image.width(), image.height()), (380, 287));
+
+        params.png.interlace = PngInterlace::Keep;
+        let output_buffer = iodine::compress_in_memory(interlaced.clone(), &params).unwrap();
+        assert!(is_interlaced(&output_buffer));
+        let output_buffer = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+        assert!(!is_interlaced(&output_buffer));
+
+        params.png.interlace = PngInterlace::Remove;
+        let output_buffer = iodine::compress_in_memory(interlaced, &params).unwrap();
+        assert!(!is_interlaced(&output_buffer));
+    }
+}
+
+#[test]
+fn remove_interlace_when_larger() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    params.png.interlace = PngInterlace::Force;
+    params.png.force_zopfli = true;
+    let interlaced = iodine::compress_in_memory(in_file, &params).unwrap();
+    assert!(is_interlaced(&interlaced));
+
+    // A fast pass cannot beat the zopfli compressed interlaced input
+    params.png.interlace = PngInterlace::Remove;
+    params.png.force_zopfli = false;
+    params.png.optimization_level = 0;
+    params.png.deflate_level = 1;
+    let output_buffer = iodine::compress_in_memory(interlaced.clone(), &params).unwrap();
+    // IHDR interlace method, byte 12 of the chunk data
+    assert_eq!(output_buffer[16 + 12], 0);
+    let image = image::load_from_memory(&output_buffer).unwrap();
+    assert_eq!((image.width(), image.height()), (380, 287));
+}
+
+#[test]
+fn convert_to_interlaced_png() {
+    let mut params = CSParameters::new();
+    params.png.interlace = PngInterlace::Force;
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let output_buffer = iodine::convert_in_memory(in_file, &params, SupportedFileTypes::Png, false).unwrap();
+    assert!(is_interlaced(&output_buffer));
+}
+
+#[test]
+fn optimization_timeout() {
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    params.png.optimization_level = 6;
+    params.png.force_zopfli = true;
+    params.png.zopfli_iterations = 50;
+    params.png.timeout = Some(std::time::Duration::from_millis(1));
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert_eq!(
+        image::load_from_memory(&in_file).unwrap().to_rgba8(),
+        image::load_from_memory(&output_buffer).unwrap().to_rgba8()
+    );
+}
+
+#[test]
+fn deflate_level() {
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+
+    params.png.deflate_level = 1;
+    let fast = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    params.png.deflate_level = 12;
+    let best = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert!(best.len() <= fast.len());
+    assert_eq!(
+        image::load_from_memory(&fast).unwrap().to_rgba8(),
+        image::load_from_memory(&best).unwrap().to_rgba8()
+    );
+
+    params.png.deflate_level = 13;
+    assert!(iodine::compress_in_memory(in_file, &params).is_err());
+}
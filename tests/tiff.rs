@@ -1,6 +1,15 @@
 use crate::cleanup::remove_compressed_test_file;
-use iodine::parameters::{CSParameters, TiffCompression, TiffDeflateLevel};
-use std::{fs::File, sync::Once};
+use image::GenericImageView;
+use iodine::parameters::{
+    CSParameters, TiffColorType, TiffCompression, TiffDeflateLevel, TiffPredictor, TiffThreshold,
+};
+use std::io::Cursor;
+use std::{fs, fs::File, sync::Once};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::Rational;
+use tiff::encoder::{colortype, TiffEncoder, TiffValue};
+use tiff::tags::{ResolutionUnit, Tag};
+use tiff::ColorType;
 mod cleanup;
 
 static INIT: Once = Once::new();
@@ -175,3 +184,384 @@ fn prevent_panic() {
     )
     .is_err());
 }
+
+#[test]
+fn assemble_and_split() {
+    let output = "tests/samples/output/assembled.tif";
+    initialize(output);
+    let mut params = CSParameters::new();
+    params.tiff.algorithm = TiffCompression::Lzw;
+    iodine::assemble_tiff(
+        vec![
+            String::from("tests/samples/rgb8.tif"),
+            String::from("tests/samples/uncompressed_드림캐쳐.png"),
+        ],
+        String::from(output),
+        &params,
+    )
+    .unwrap();
+    assert_eq!(infer::get_from_path(output).unwrap().unwrap().mime_type(), "image/tiff");
+
+    let pages = iodine::split_tiff(String::from(output), String::from("tests/samples/output"), &params).unwrap();
+    assert_eq!(
+        pages,
+        vec![
+            "tests/samples/output/assembled_0.tif",
+            "tests/samples/output/assembled_1.tif"
+        ]
+    );
+    assert_eq!(image::image_dimensions(&pages[0]).unwrap(), (128, 72));
+    assert_eq!(image::image_dimensions(&pages[1]).unwrap(), (380, 287));
+    remove_compressed_test_file(output);
+    pages.iter().for_each(|p| remove_compressed_test_file(p));
+}
+
+#[test]
+fn multipage_compress() {
+    let mut params = CSParameters::new();
+    params.tiff.algorithm = TiffCompression::Deflate;
+    let multipage = iodine::assemble_tiff_in_memory(
+        vec![
+            fs::read("tests/samples/rgb8.tif").unwrap(),
+            fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap(),
+            fs::read("tests/samples/rgb8.tif").unwrap(),
+        ],
+        &params,
+    )
+    .unwrap();
+
+    params.width = 50;
+    params.height = 20;
+    let compressed = iodine::compress_in_memory(multipage, &params).unwrap();
+    let pages = iodine::split_tiff_in_memory(compressed, &CSParameters::new()).unwrap();
+    assert_eq!(pages.len(), 3);
+    for page in pages {
+        assert_eq!(image::load_from_memory(&page).unwrap().dimensions(), (50, 20));
+    }
+}
+
+#[test]
+fn color_types() {
+    let gray8: Vec<u8> = (0..64 * 32).map(|i| (i % 251) as u8).collect();
+    let gray16: Vec<u16> = (0..64 * 32).map(|i| (i * 31) as u16).collect();
+    let rgb16: Vec<u16> = (0..64 * 32 * 3).map(|i| (i * 17) as u16).collect();
+    let rgba16: Vec<u16> = (0..64 * 32 * 4).map(|i| (i * 13) as u16).collect();
+    let cmyk8: Vec<u8> = (0..64 * 32 * 4).map(|i| (i % 253) as u8).collect();
+    let inputs = [
+        encode::<colortype::Gray8>(&gray8),
+        encode::<colortype::Gray16>(&gray16),
+        encode::<colortype::RGB16>(&rgb16),
+        encode::<colortype::RGBA16>(&rgba16),
+        encode::<colortype::CMYK8>(&cmyk8),
+        bilevel(),
+    ];
+
+    for algorithm in [TiffCompression::Lzw, TiffCompression::Packbits] {
+        let mut params = CSParameters::new();
+        params.tiff.algorithm = algorithm;
+        for input in &inputs {
+            let compressed = iodine::compress_in_memory(input.clone(), &params).unwrap();
+            assert_eq!(decode(&compressed), decode(input));
+        }
+    }
+}
+
+#[test]
+fn color_type_conversion() {
+    let gray16: Vec<u16> = (0..64 * 32).map(|i| (i * 31) as u16).collect();
+    let mut params = CSParameters::new();
+    params.tiff.color_type = TiffColorType::Rgb8;
+    let compressed = iodine::compress_in_memory(encode::<colortype::Gray16>(&gray16), &params).unwrap();
+    assert_eq!(decode(&compressed).0, ColorType::RGB(8));
+
+    params.tiff.color_type = TiffColorType::Gray8;
+    let compressed = iodine::compress_in_memory(bilevel(), &params).unwrap();
+    assert_eq!(decode(&compressed).0, ColorType::Gray(8));
+}
+
+#[test]
+fn horizontal_predictor() {
+    let mut params = CSParameters::new();
+    params.tiff.algorithm = TiffCompression::Uncompressed;
+    let photo = iodine::assemble_tiff_in_memory(
+        vec![fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap()],
+        &params,
+    )
+    .unwrap();
+    let gray16: Vec<u16> = (0..64 * 32).map(|i| (i * 31) as u16).collect();
+
+    for algorithm in [TiffCompression::Lzw, TiffCompression::Deflate] {
+        params.tiff.algorithm = algorithm;
+        params.tiff.predictor = TiffPredictor::None;
+        let plain = iodine::compress_in_memory(photo.clone(), &params).unwrap();
+        params.tiff.predictor = TiffPredictor::Horizontal;
+        let predicted = iodine::compress_in_memory(photo.clone(), &params).unwrap();
+        assert!(predicted.len() < plain.len());
+        assert_eq!(decode(&predicted), decode(&photo));
+
+        let input = encode::<colortype::Gray16>(&gray16);
+        let predicted = iodine::compress_in_memory(input.clone(), &params).unwrap();
+        assert_eq!(decode(&predicted), decode(&input));
+    }
+}
+
+#[test]
+fn jpeg() {
+    let mut params = CSParameters::new();
+    params.tiff.algorithm = TiffCompression::Uncompressed;
+    params.tiff.color_type = TiffColorType::Rgb8;
+    let input = iodine::assemble_tiff_in_memory(
+        vec![fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap()],
+        &params,
+    )
+    .unwrap();
+
+    params.tiff.algorithm = TiffCompression::Jpeg;
+    params.tiff.jpeg_quality = 90;
+    let compressed = iodine::compress_in_memory(input.clone(), &params).unwrap();
+    assert!(compressed.len() < input.len() / 4);
+
+    let mut decoder = Decoder::new(Cursor::new(&compressed)).unwrap();
+    assert_eq!(decoder.get_tag_u32(Tag::Compression).unwrap(), 7);
+    assert_eq!(decoder.colortype().unwrap(), ColorType::YCbCr(8));
+
+    params.tiff.algorithm = TiffCompression::Uncompressed;
+    let (color_type, pixels) = decode(&iodine::compress_in_memory(compressed, &params).unwrap());
+    let (_, original) = decode(&input);
+    assert_eq!(color_type, ColorType::RGB(8));
+    let difference: u64 = pixels.iter().zip(&original).map(|(a, b)| a.abs_diff(*b) as u64).sum();
+    assert!(difference / (original.len() as u64) < 2);
+
+    params.tiff.algorithm = TiffCompression::Jpeg;
+    params.tiff.color_type = TiffColorType::Original;
+    let rgba = fs::read("tests/samples/rgba8.tif").unwrap();
+    assert!(iodine::compress_in_memory(rgba.clone(), &params).is_err());
+    params.tiff.color_type = TiffColorType::Rgb8;
+    assert!(iodine::compress_in_memory(rgba, &params).is_ok());
+}
+
+#[test]
+fn ccitt() {
+    let scan = scan();
+    let mut params = CSParameters::new();
+    params.tiff.color_type = TiffColorType::Gray8;
+    let deflate = iodine::assemble_tiff_in_memory(vec![scan.clone()], &params).unwrap();
+
+    params.tiff.algorithm = TiffCompression::CcittGroup4;
+    params.tiff.threshold = TiffThreshold::Adaptive;
+    let group4 = iodine::assemble_tiff_in_memory(vec![scan.clone()], &params).unwrap();
+    assert!(group4.len() < deflate.len() / 10);
+    let mut decoder = Decoder::new(Cursor::new(&group4)).unwrap();
+    assert_eq!(decoder.get_tag_u32(Tag::Compression).unwrap(), 4);
+    let (color_type, pixels) = decode(&group4);
+    assert_eq!(color_type, ColorType::Gray(1));
+    let white: Vec<bool> = pixels
+        .chunks_exact(64)
+        .flat_map(|row| (0..512).map(move |x| row[x / 8] >> (7 - x % 8) & 1 == 1))
+        .collect();
+    assert_eq!(white, scan_text());
+
+    params.tiff.algorithm = TiffCompression::CcittGroup3;
+    let group3 = iodine::assemble_tiff_in_memory(vec![scan], &params).unwrap();
+    assert!(group3.len() < deflate.len() / 4);
+    let mut decoder = Decoder::new(Cursor::new(&group3)).unwrap();
+    assert_eq!(decoder.get_tag_u32(Tag::Compression).unwrap(), 3);
+    let offset = decoder.get_tag_u32(Tag::StripOffsets).unwrap() as usize;
+    let length = decoder.get_tag_u32(Tag::StripByteCounts).unwrap() as usize;
+    assert_eq!(decode_group3(&group3[offset..offset + length], 512), scan_text());
+}
+
+#[test]
+fn ccitt_threshold() {
+    let mut params = CSParameters::new();
+    params.tiff.algorithm = TiffCompression::CcittGroup4;
+    params.tiff.threshold = TiffThreshold::Global;
+    let global = iodine::assemble_tiff_in_memory(vec![scan()], &params).unwrap();
+    let (_, pixels) = decode(&global);
+    // The darker half of the page goes black with a single threshold
+    assert_eq!(pixels[0], 0);
+    assert_ne!(pixels[63], 0);
+
+    params.tiff.threshold = TiffThreshold::Adaptive;
+    params.tiff.algorithm = TiffCompression::CcittGroup3;
+    assert!(iodine::assemble_tiff_in_memory(vec![scan()], &params).is_ok());
+
+    params.width = 70000;
+    params.height = 1;
+    assert!(iodine::assemble_tiff_in_memory(vec![scan()], &params).is_err());
+}
+
+#[test]
+fn keep_metadata() {
+    let mut params = CSParameters::new();
+    params.keep_metadata = true;
+    let compressed = iodine::compress_in_memory(with_metadata(), &params).unwrap();
+
+    let mut decoder = Decoder::new(Cursor::new(&compressed)).unwrap();
+    assert_eq!(decoder.get_tag_u32_vec(Tag::XResolution).unwrap(), [300, 1]);
+    assert_eq!(decoder.get_tag_u32_vec(Tag::YResolution).unwrap(), [300, 1]);
+    assert_eq!(decoder.get_tag_u32(Tag::ResolutionUnit).unwrap(), 2);
+    assert_eq!(decoder.get_tag(Tag::Artist).unwrap().into_string().unwrap(), "Artist");
+    assert_eq!(
+        decoder.get_tag(Tag::Copyright).unwrap().into_string().unwrap(),
+        "Copyright"
+    );
+    assert_eq!(
+        decoder.get_tag(Tag::DateTime).unwrap().into_string().unwrap(),
+        "2024:01:02 03:04:05"
+    );
+    assert_eq!(
+        decoder.get_tag(Tag::Unknown(269)).unwrap().into_string().unwrap(),
+        "Document"
+    );
+    assert_eq!(decoder.get_tag_u32_vec(Tag::IccProfile).unwrap(), [1, 2, 3, 4]);
+
+    let exif = exif::Reader::new().read_raw(compressed).unwrap();
+    let date = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY).unwrap();
+    assert!(matches!(&date.value, exif::Value::Ascii(v) if v[0] == b"2024:01:02 03:04:05"));
+    let exposure = exif.get_field(exif::Tag::ExposureTime, exif::In::PRIMARY).unwrap();
+    assert!(matches!(&exposure.value, exif::Value::Rational(v) if v[0].num == 1 && v[0].denom == 125));
+
+    // The gray profile does not apply to RGB pages
+    params.tiff.color_type = TiffColorType::Rgb8;
+    let compressed = iodine::compress_in_memory(with_metadata(), &params).unwrap();
+    let mut decoder = Decoder::new(Cursor::new(&compressed)).unwrap();
+    assert!(decoder.find_tag(Tag::IccProfile).unwrap().is_none());
+    assert_eq!(decoder.get_tag_u32_vec(Tag::XResolution).unwrap(), [300, 1]);
+
+    params.keep_metadata = false;
+    let compressed = iodine::compress_in_memory(with_metadata(), &params).unwrap();
+    let mut decoder = Decoder::new(Cursor::new(&compressed)).unwrap();
+    assert_eq!(decoder.get_tag_u32_vec(Tag::XResolution).unwrap(), [1, 1]);
+    assert!(decoder.find_tag(Tag::Artist).unwrap().is_none());
+    assert!(decoder.find_tag(Tag::ExifDirectory).unwrap().is_none());
+}
+
+/// A 64x32 gray TIFF at 300 DPI, with an ICC profile, descriptive tags and an EXIF IFD.
+fn with_metadata() -> Vec<u8> {
+    let mut output = Cursor::new(vec![]);
+    let mut encoder = TiffEncoder::new(&mut output).unwrap();
+    let mut exif = encoder.extra_directory().unwrap();
+    exif.write_tag(Tag::Unknown(0x9003), "2024:01:02 03:04:05").unwrap();
+    exif.write_tag(Tag::Unknown(0x829A), Rational { n: 1, d: 125 }).unwrap();
+    let exif = exif.finish_with_offsets().unwrap().offset;
+
+    let mut image = encoder.new_image::<colortype::Gray8>(64, 32).unwrap();
+    image.resolution(ResolutionUnit::Inch, Rational { n: 300, d: 1 });
+    let directory = image.encoder();
+    directory.write_tag(Tag::Artist, "Artist").unwrap();
+    directory.write_tag(Tag::Copyright, "Copyright").unwrap();
+    directory.write_tag(Tag::DateTime, "2024:01:02 03:04:05").unwrap();
+    directory.write_tag(Tag::Unknown(269), "Document").unwrap();
+    directory.write_tag(Tag::IccProfile, &[1u8, 2, 3, 4][..]).unwrap();
+    directory.write_tag(Tag::ExifDirectory, exif).unwrap();
+    image.write_data(&[128u8; 64 * 32]).unwrap();
+    output.into_inner()
+}
+
+/// A 512x256 PNG of dark bars on a noisy background getting lighter from left to right, like an unevenly lit scan.
+fn scan() -> Vec<u8> {
+    let text = scan_text();
+    let image = image::GrayImage::from_fn(512, 256, |x, y| {
+        let background = 80 + x * 150 / 511;
+        let noise = (x * 512 + y).wrapping_mul(2654435761) >> 28;
+        match text[(y * 512 + x) as usize] {
+            true => image::Luma([(background + noise - 8) as u8]),
+            false => image::Luma([(background / 3 + noise) as u8]),
+        }
+    });
+    let mut output = Cursor::new(vec![]);
+    image.write_to(&mut output, image::ImageFormat::Png).unwrap();
+    output.into_inner()
+}
+
+/// Whether each pixel of the scan is background.
+fn scan_text() -> Vec<bool> {
+    (0..256 * 512)
+        .map(|i| {
+            let (x, y) = (i % 512, i / 512);
+            !((64..192).contains(&y) && (x / 16) % 4 == 1)
+        })
+        .collect()
+}
+
+/// Decodes CCITT T.4 one-dimensional rows into whether each pixel is white. The `fax` crate Group 3 decoder
+/// does not accept an EOL before the first row, so the codes are matched here.
+fn decode_group3(data: &[u8], width: u16) -> Vec<bool> {
+    let bits: Vec<bool> = fax::slice_bits(data).collect();
+    let code = |pos: usize, len: u8| {
+        bits[pos..pos + len as usize]
+            .iter()
+            .fold(0, |code, &bit| code << 1 | bit as u16)
+    };
+    let mut pixels = vec![];
+    let mut pos = 0;
+    // Every row starts with an EOL, and the page ends with six of them
+    while code(pos, 12) == 1 && code(pos + 12, 12) != 1 {
+        pos += 12;
+        let (mut white, mut x) = (true, 0);
+        loop {
+            let codes = if white {
+                &fax::maps::white::ENTRIES
+            } else {
+                &fax::maps::black::ENTRIES
+            };
+            let (run, bits) = codes.iter().find(|(_, bits)| code(pos, bits.len) == bits.data).unwrap();
+            pos += bits.len as usize;
+            x += run;
+            pixels.extend(std::iter::repeat_n(white, *run as usize));
+            if *run < 64 {
+                if x == width {
+                    break;
+                }
+                white = !white;
+            }
+        }
+    }
+    assert!((0..6).all(|i| code(pos + i * 12, 12) == 1));
+    pixels
+}
+
+fn encode<C: colortype::ColorType>(data: &[C::Inner]) -> Vec<u8>
+where
+    [C::Inner]: TiffValue,
+{
+    let mut output = Cursor::new(vec![]);
+    TiffEncoder::new(&mut output)
+        .unwrap()
+        .write_image::<C>(64, 32, data)
+        .unwrap();
+    output.into_inner()
+}
+
+/// A 64x32 black and white checkerboard with 1 bit per pixel and black as zero.
+fn bilevel() -> Vec<u8> {
+    let data: Vec<u8> = (0..32)
+        .flat_map(|y| [if y % 2 == 0 { 0xF0 } else { 0x0F }; 8])
+        .collect();
+    let mut output = Cursor::new(vec![]);
+    let mut encoder = TiffEncoder::new(&mut output).unwrap();
+    let mut directory = encoder.image_directory().unwrap();
+    let offset = directory.write_data(data.as_slice()).unwrap() as u32;
+    directory.write_tag(Tag::ImageWidth, 64u32).unwrap();
+    directory.write_tag(Tag::ImageLength, 32u32).unwrap();
+    directory.write_tag(Tag::BitsPerSample, 1u16).unwrap();
+    directory.write_tag(Tag::Compression, 1u16).unwrap();
+    directory.write_tag(Tag::PhotometricInterpretation, 1u16).unwrap();
+    directory.write_tag(Tag::StripOffsets, offset).unwrap();
+    directory.write_tag(Tag::SamplesPerPixel, 1u16).unwrap();
+    directory.write_tag(Tag::RowsPerStrip, 32u32).unwrap();
+    directory.write_tag(Tag::StripByteCounts, data.len() as u32).unwrap();
+    directory.finish().unwrap();
+    output.into_inner()
+}
+
+fn decode(data: &[u8]) -> (ColorType, Vec<u8>) {
+    let mut decoder = Decoder::new(Cursor::new(data)).unwrap();
+    let pixels = match decoder.read_image().unwrap() {
+        DecodingResult::U8(p) => p,
+        DecodingResult::U16(p) => p.iter().flat_map(|v| v.to_ne_bytes()).collect(),
+        _ => unreachable!(),
+    };
+    (decoder.colortype().unwrap(), pixels)
+}
@@ -1,6 +1,7 @@
 use crate::cleanup::remove_compressed_test_file;
-use iodine::parameters::CSParameters;
-use std::{fs::File, sync::Once};
+use iodine::parameters::{CSParameters, WebPImageHint};
+use std::io::Cursor;
+use std::{fs, fs::File, sync::Once};
 
 mod cleanup;
 
@@ -156,3 +157,141 @@ fn compress_animated_80() {
     assert_eq!(infer::get_from_path(output).unwrap().unwrap().mime_type(), "image/webp");
     remove_compressed_test_file(output)
 }
+
+#[test]
+fn encoding_settings() {
+    let mut params = CSParameters::new();
+    params.webp.method = 6;
+    params.webp.sns_strength = 80;
+    params.webp.filter_strength = 20;
+    params.webp.filter_sharpness = 3;
+    params.webp.segments = 2;
+    params.webp.preprocessing = 2;
+    params.webp.alpha_quality = 50;
+    params.webp.pass = 4;
+    params.webp.image_hint = WebPImageHint::Photo;
+
+    for input in [
+        "tests/samples/uncompressed_家.webp",
+        "tests/samples/uncompressed_animated.webp",
+    ] {
+        let in_file = fs::read(input).unwrap();
+        let output_buffer = iodine::compress_in_memory(in_file, &params).unwrap();
+        assert_eq!(infer::get(&output_buffer).unwrap().mime_type(), "image/webp");
+    }
+
+    params.webp.method = 7;
+    let in_file = fs::read("tests/samples/uncompressed_家.webp").unwrap();
+    assert!(iodine::compress_in_memory(in_file, &params).is_err());
+}
+
+#[test]
+fn lossless_exact() {
+    let image = image::RgbaImage::from_fn(64, 64, |x, y| {
+        image::Rgba([(x * 4) as u8, (y * 4) as u8, 200, if x < 32 { 0 } else { 255 }])
+    });
+    let mut in_file = vec![];
+    image::codecs::webp::WebPEncoder::new_lossless(Cursor::new(&mut in_file))
+        .encode(image.as_raw(), 64, 64, image::ExtendedColorType::Rgba8)
+        .unwrap();
+
+    let mut params = CSParameters::new();
+    params.webp.lossless = true;
+    params.webp.exact = true;
+    params.webp.image_hint = WebPImageHint::Graph;
+    let output_buffer = iodine::compress_in_memory(in_file, &params).unwrap();
+    assert_eq!(image::load_from_memory(&output_buffer).unwrap().to_rgba8(), image);
+}
+
+/// Returns the background color bytes and the loop count of the ANIM chunk.
+fn animation_params(buffer: &[u8]) -> ([u8; 4], u16) {
+    let offset = buffer.windows(4).position(|w| w == b"ANIM").unwrap() + 8;
+    let background = buffer[offset..offset + 4].try_into().unwrap();
+    (background, u16::from_le_bytes([buffer[offset + 4], buffer[offset + 5]]))
+}
+
+fn animation_frames(buffer: &[u8]) -> Vec<image::Frame> {
+    use image::AnimationDecoder;
+    image::codecs::webp::WebPDecoder::new(Cursor::new(buffer))
+        .unwrap()
+        .into_frames()
+        .collect_frames()
+        .unwrap()
+}
+
+fn frame_delays(frames: &[image::Frame]) -> Vec<(u32, u32)> {
+    frames.iter().map(|f| f.delay().numer_denom_ms()).collect()
+}
+
+fn animated_sample() -> Vec<u8> {
+    let mut in_file = fs::read("tests/samples/uncompressed_animated.webp").unwrap();
+    // Non default background and loop count, to check they are carried over
+    let offset = in_file.windows(4).position(|w| w == b"ANIM").unwrap() + 8;
+    in_file[offset..offset + 6].copy_from_slice(&[10, 20, 30, 255, 3, 0]);
+    in_file
+}
+
+#[test]
+fn animated_timing() {
+    let in_file = animated_sample();
+    let original_frames = animation_frames(&in_file);
+
+    let output_buffer = iodine::compress_in_memory(in_file.clone(), &CSParameters::new()).unwrap();
+    let frames = animation_frames(&output_buffer);
+    assert_eq!(frame_delays(&frames), frame_delays(&original_frames));
+    assert_eq!(animation_params(&output_buffer), ([10, 20, 30, 255], 3));
+}
+
+#[test]
+fn resize_animated_timing() {
+    let in_file = animated_sample();
+    let original_frames = animation_frames(&in_file);
+    let mut params = CSParameters::new();
+    params.width = 190;
+
+    let output_buffer = iodine::compress_in_memory(in_file, &params).unwrap();
+    let frames = animation_frames(&output_buffer);
+    assert_eq!(frame_delays(&frames), frame_delays(&original_frames));
+    assert_eq!(animation_params(&output_buffer), ([10, 20, 30, 255], 3));
+    assert_eq!(frames[0].buffer().dimensions(), (190, 200));
+}
+
+#[test]
+fn lossless_animated_blending() {
+    let in_file = animated_sample();
+    let original_frames = animation_frames(&in_file);
+    let mut params = CSParameters::new();
+    params.webp.lossless = true;
+    params.webp.exact = true;
+
+    let output_buffer = iodine::compress_in_memory(in_file, &params).unwrap();
+    let frames = animation_frames(&output_buffer);
+    assert_eq!(frames.len(), original_frames.len());
+    // Lossy source frames are decoded by libwebp before re-encoding, which rounds slightly differently
+    for (frame, original) in frames.iter().zip(original_frames.iter()) {
+        assert!(frame
+            .buffer()
+            .as_raw()
+            .iter()
+            .zip(original.buffer().as_raw().iter())
+            .all(|(a, b)| a.abs_diff(*b) <= 3));
+    }
+}
+
+#[test]
+fn animation_encoder_options() {
+    let in_file = animated_sample();
+    let mut params = CSParameters::new();
+    params.width = 100;
+    let default = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+
+    params.webp.allow_mixed = true;
+    params.webp.minimize_size = true;
+    params.webp.kmin = 9;
+    params.webp.kmax = 17;
+    let optimized = iodine::compress_in_memory(in_file.clone(), &params).unwrap();
+    assert!(optimized.len() <= default.len());
+    // Frames made identical by the downscale are merged, the total duration stays the same
+    let duration = |buffer: &[u8]| frame_delays(&animation_frames(buffer)).iter().map(|d| d.0).sum::<u32>();
+    assert_eq!(duration(&optimized), duration(&in_file));
+}